@@ -0,0 +1,194 @@
+//! Assembling sasm at compile time.
+//!
+//! ```ignore
+//! const BOOT : &[u8] = sasm! {
+//!     start: mov 0x10, r0
+//!     ret
+//! };
+//! const FIRMWARE : &[u8] = sasm_file!("fw/main.sasm");
+//!
+//! mod boot {
+//!     sasm_macros::sasm_symbols! {
+//!         start: mov 0x10, r0
+//!         ret
+//!     }
+//! }
+//! // boot::BYTES, boot::START
+//! ```
+//!
+//! Source written inline keeps its lines, so directives and labels work as in a file, but Rust's
+//! tokenizer sees it first: comments are `//` and `/* */`, and strings and chars must be valid Rust
+//! literals. Files are found relative to the crate's `Cargo.toml`, and the crate is rebuilt when
+//! they or anything they include change. Assembler errors come out as compile errors, on the line
+//! they're about where it can be told.
+
+use std::{collections::{HashMap, HashSet}, iter::repeat};
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use sasm_lib::{Program, SymbolKind, assemble, assemble_file, build, source, utils::Error};
+
+/// Source text rebuilt from tokens, keeping their lines and columns
+#[derive(Default)]
+struct Source {
+    text : String,
+    /// Where the last token ended
+    at : Option<(usize, usize)>,
+    /// Where every token starts in the text, as a line number and a column
+    tokens : Vec<(usize, usize, Span)>,
+}
+
+impl Source {
+    fn new(tokens : TokenStream) -> Self {
+        let mut res = Self::default();
+        res.extend(tokens);
+        res
+    }
+
+    fn extend(&mut self, tokens : TokenStream) {
+        for token in tokens {
+            match &token {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(group.span_open(), open);
+                    self.extend(group.stream());
+                    self.push(group.span_close(), close);
+                },
+                token => self.push(token.span(), &token.to_string()),
+            }
+        }
+    }
+
+    fn push(&mut self, span : Span, text : &str) {
+        let (line, column) = (span.line(), span.column());
+        match self.at {
+            Some((last, _)) if line > last => {
+                self.text.extend(repeat('\n').take(line - last));
+                self.text.extend(repeat(' ').take(column.saturating_sub(1)));
+            },
+            Some((_, last)) => self.text.extend(repeat(' ').take(column.saturating_sub(last))),
+            None => (),
+        }
+        let number = self.text.matches('\n').count() + 1;
+        let start = self.text.rfind('\n').map_or(0, |at| at + 1);
+        self.tokens.push((number, self.text.len() - start, span));
+
+        self.text.push_str(text);
+        let end = span.end();
+        self.at = Some((end.line(), end.column()));
+    }
+
+    /// The token `err` is most likely about
+    fn locate(&self, err : &Error) -> Span {
+        let Ok(lines) = source::load("<input>", None, &self.text) else { return Span::call_site() };
+        match build::locate(err, &lines) {
+            Some((line, column, _)) if line.file == "<input>" => self.tokens.iter()
+                .rev()
+                .find(|(number, at, _)| (*number, *at) <= (line.number, column))
+                .map_or(Span::call_site(), |(_, _, span)| *span),
+            _ => Span::call_site(),
+        }
+    }
+}
+
+/// `compile_error!(message)`, pointing at `span`
+fn error(message : &str, span : Span) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let mut group = Group::new(Delimiter::Parenthesis, TokenTree::Literal(literal).into());
+    group.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    [TokenTree::Ident(Ident::new("compile_error", span)), TokenTree::Punct(bang), TokenTree::Group(group)].into_iter().collect()
+}
+
+/// The program in the file named by the string literal in `input`, and tokens to rebuild the
+/// crate when any of its files change
+fn load(input : TokenStream) -> Result<(Program, String), TokenStream> {
+    let mut tokens = input.into_iter();
+    let (Some(TokenTree::Literal(literal)), None) = (tokens.next(), tokens.next()) else {
+        return Err(error("expected the path to a sasm file, as a string", Span::call_site()))
+    };
+    let text = literal.to_string();
+    let Some(path) = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) else {
+        return Err(error("expected the path to a sasm file, as a string", literal.span()))
+    };
+
+    let root = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let fpath = std::path::Path::new(&root).join(path).to_string_lossy().into_owned();
+    let program = assemble_file(&fpath).map_err(|err| error(&format!("{path}: {err}"), literal.span()))?;
+
+    // Including the files' bytes is what makes cargo watch them
+    let mut seen = HashSet::new();
+    let track = program.lines.iter()
+        .filter(|line| !line.file.starts_with('<') && seen.insert(line.file.clone()))
+        .map(|line| format!("const _ : &[u8] = include_bytes!({:?});", line.file))
+        .collect();
+    Ok((program, track))
+}
+
+/// The bytes of `program`, as a `&[u8]` expression
+fn bytes(program : &Program, track : &str) -> TokenStream {
+    format!("{{ {track} {} }}", Literal::byte_string(&program.bytes())).parse().unwrap()
+}
+
+/// `BYTES` with the program's bytes, and a `u16` constant for every label and `.equ`, named in
+/// upper case
+fn symbols(program : &Program, track : &str) -> TokenStream {
+    let mut res = format!("{track} pub const BYTES : &[u8] = {};", Literal::byte_string(&program.bytes()));
+    let mut seen = HashMap::from([("BYTES".to_string(), "the bytes")]);
+    for symbol in program.symbols.iter().filter(|symbol| symbol.kind != SymbolKind::Section) {
+        let name = symbol.name.to_uppercase();
+        let valid = name.starts_with(|c : char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            continue
+        }
+        if let Some(other) = seen.insert(name.clone(), &symbol.name) {
+            return error(&format!("{other} and {} would both be {name}", symbol.name), Span::call_site())
+        }
+        res += &format!(" pub const {name} : u16 = {:#06X};", symbol.address);
+    }
+    res.parse().unwrap()
+}
+
+/// Assembles the code it's given into a `&[u8]`
+#[proc_macro]
+pub fn sasm(input : TokenStream) -> TokenStream {
+    let source = Source::new(input);
+    match assemble(&source.text) {
+        Ok(program) => bytes(&program, ""),
+        Err(err) => error(&err.to_string(), source.locate(&err)),
+    }
+}
+
+/// Assembles a file, relative to the crate's `Cargo.toml`, into a `&[u8]`
+#[proc_macro]
+pub fn sasm_file(input : TokenStream) -> TokenStream {
+    match load(input) {
+        Ok((program, track)) => bytes(&program, &track),
+        Err(err) => err,
+    }
+}
+
+/// Assembles the code it's given into a `BYTES` constant, with a constant for every symbol
+#[proc_macro]
+pub fn sasm_symbols(input : TokenStream) -> TokenStream {
+    let source = Source::new(input);
+    match assemble(&source.text) {
+        Ok(program) => symbols(&program, ""),
+        Err(err) => error(&err.to_string(), source.locate(&err)),
+    }
+}
+
+/// Assembles a file like [`sasm_file!`], into constants like [`sasm_symbols!`]
+#[proc_macro]
+pub fn sasm_file_symbols(input : TokenStream) -> TokenStream {
+    match load(input) {
+        Ok((program, track)) => symbols(&program, &track),
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,78 @@
+use std::process::Command;
+
+use sasm_macros::{sasm, sasm_file};
+
+const BOOT : &[u8] = sasm! {
+    start: mov 0x10, r0 // Comments are Rust's
+    call double
+    ret
+    double: add r0, r0
+    ret
+};
+
+mod boot {
+    sasm_macros::sasm_symbols! {
+        .equ SIZE, 4
+        start: mov SIZE, r0
+        ret
+    }
+}
+
+const FIRMWARE : &[u8] = sasm_file!("tests/fixtures/main.sasm");
+
+mod firmware {
+    sasm_macros::sasm_file_symbols!("tests/fixtures/main.sasm");
+}
+
+/// What rustc says about a crate using the macros in `code`, which must fail to build
+fn build_error(name : &str, code : &str) -> String {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    let manifest = format!(
+        "[package]\nname = \"{name}\"\nversion = \"0.0.0\"\nedition = \"2021\"\n\n[workspace]\n\n[dependencies]\nsasm-macros = {{ path = {:?} }}\n",
+        env!("CARGO_MANIFEST_DIR"),
+    );
+    std::fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+    std::fs::write(dir.join("src/lib.rs"), code).unwrap();
+
+    // Its own target directory, as the one running the tests is locked
+    let output = Command::new(env!("CARGO"))
+        .args(["check", "--offline", "--quiet"])
+        .current_dir(&dir)
+        .env("CARGO_TARGET_DIR", dir.join("target"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn inline() {
+    let code = "start: mov 0x10, r0\ncall double\nret\ndouble: add r0, r0\nret";
+    assert_eq!(BOOT, sasm_lib::compile(code).unwrap());
+}
+
+#[test]
+fn symbols() {
+    assert_eq!(boot::BYTES, sasm_lib::compile(".equ SIZE, 4\nstart: mov SIZE, r0\nret").unwrap());
+    assert_eq!((boot::START, boot::SIZE), (0, 4));
+}
+
+#[test]
+fn files() {
+    let code = "start: mov 0x10, r0\ncall double\nret\ndouble: add r0, r0\nret";
+    assert_eq!(FIRMWARE, sasm_lib::compile(code).unwrap());
+    assert_eq!(firmware::BYTES, FIRMWARE);
+    let double = sasm_lib::assemble(code).unwrap().symbols.into_iter().find(|symbol| symbol.name == "double").unwrap();
+    assert_eq!((firmware::START, firmware::DOUBLE), (0, double.address));
+}
+
+#[test]
+fn errors() {
+    let code = "const _ : &[u8] = sasm_macros::sasm! {\n    start: mov 0x10, r0\n    bogus r0\n    ret\n};\n";
+    let stderr = build_error("sasm_bad_line", code);
+    assert!(stderr.contains("--> src/lib.rs:3:5"), "{stderr}");
+
+    let stderr = build_error("sasm_same_constant", "sasm_macros::sasm_symbols! {\n    foo: nop\n    FOO: nop\n}\n");
+    assert!(stderr.contains("foo and FOO would both be FOO"), "{stderr}");
+}
@@ -0,0 +1,157 @@
+//! Assembling sources from build scripts.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     sasm_lib::build::Assembler::new()
+//!         .file("fw/main.sasm")
+//!         .include_dir("fw/lib")
+//!         .out_dir_env()
+//!         .compile("fw");
+//! }
+//!
+//! // src/lib.rs
+//! const FIRMWARE : &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/fw.bin"));
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use crate::{Program, map, parser::{assemble_lines, parse_lines}, source::{self, Line}, testing, utils::{Error, Result}};
+
+/// Assembles files into a binary and a symbol map, `<name>.bin` and `<name>.map`
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    files : Vec<PathBuf>,
+    include_dirs : Vec<PathBuf>,
+    out_dir : Option<PathBuf>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file, assembled after the ones before it
+    pub fn file(&mut self, path : impl AsRef<Path>) -> &mut Self {
+        self.files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a directory to look for `.include "path"` in, when it isn't relative to the including file
+    pub fn include_dir(&mut self, path : impl AsRef<Path>) -> &mut Self {
+        self.include_dirs.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn out_dir(&mut self, path : impl AsRef<Path>) -> &mut Self {
+        self.out_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Writes to the `OUT_DIR` cargo gives build scripts
+    pub fn out_dir_env(&mut self) -> &mut Self {
+        self.out_dir = std::env::var_os("OUT_DIR").map(PathBuf::from);
+        self
+    }
+
+    /// Assembles the files and writes the results, returning the program. Errors come rendered
+    /// like rustc's, ready to print.
+    pub fn try_compile(&self, name : &str) -> Result<Program> {
+        self.write(name, self.load()?)
+    }
+
+    /// The lines of every file, with what they include
+    fn load(&self) -> Result<Vec<Line>> {
+        if let Some(path) = self.files.iter().find(|path| path.to_string_lossy().contains('"')) {
+            return Err(Error::External(format!("error: {} can't be included, its path has a '\"' in it", path.display())))
+        }
+
+        // Every file is included from a file of its own, so each is only read once
+        let code : String = self.files.iter().map(|path| format!(".include \"{}\"\n", path.display())).collect();
+        source::load_with("<build>", None, &self.include_dirs, &code).map_err(|err| Error::External(render(&err, &[])))
+    }
+
+    /// Assembles `lines` and writes the results
+    fn write(&self, name : &str, lines : Vec<Line>) -> Result<Program> {
+        let out_dir = self.out_dir.as_ref().ok_or(Error::External("no output directory set".to_string()))?;
+        let program = assemble_lines(lines.clone()).map_err(|err| Error::External(render(&err, &lines)))?;
+        let write = |extension : &str, bytes : &[u8]| {
+            let path = out_dir.join(format!("{name}.{extension}"));
+            std::fs::write(&path, bytes).map_err(|err| Error::External(format!("{}: {err}", path.display())))
+        };
+        write("bin", &program.bytes())?;
+        write("map", map(&program).as_bytes())?;
+        Ok(program)
+    }
+
+    /// Like [`Assembler::try_compile`], but telling cargo to rerun the build script when any of
+    /// the files change, and printing any error and failing the build script. The files are
+    /// watched even when they don't assemble, so fixing them is what reruns it.
+    pub fn compile(&self, name : &str) -> Program {
+        // Includes that can't be found may turn up in the include directories later
+        for path in self.files.iter().chain(&self.include_dirs) {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+        let res = self.load().and_then(|lines| {
+            for file in files(&lines) {
+                println!("cargo:rerun-if-changed={file}");
+            }
+            self.write(name, lines)
+        });
+        match res {
+            Ok(program) => program,
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1)
+            },
+        }
+    }
+}
+
+/// The files `lines` came from, bundled ones aside
+fn files(lines : &[Line]) -> Vec<&str> {
+    let mut res : Vec<&str> = Vec::new();
+    for line in lines.iter().filter(|line| !line.file.starts_with('<')) {
+        if !res.contains(&line.file.as_str()) {
+            res.push(&line.file);
+        }
+    }
+    res
+}
+
+/// The line `err` is most likely about, and the column and length of the part of it that's wrong,
+/// for tools pointing at errors in sources they've loaded
+pub fn locate<'a>(err : &Error, lines : &'a [Line]) -> Option<(&'a Line, usize, usize)> {
+    let whole = |line : &'a Line| {
+        let start = line.code.len() - line.code.trim_start().len();
+        (line, start, line.code.trim().len().max(1))
+    };
+
+    if let Error::NoSuchIdentifier(name) = err {
+        let is_word = |c : char| c.is_alphanumeric() || c == '_';
+        return lines.iter().find_map(|line| {
+            let column = line.code.match_indices(name.as_str()).find(|(at, _)| {
+                !line.code[..*at].ends_with(is_word) && !line.code[at + name.len()..].starts_with(is_word)
+            })?.0;
+            Some((line, column, name.len()))
+        })
+    }
+
+    // Errors come up in order, so it's on the first line the code up to fails to parse on
+    let mut code = lines.to_vec();
+    testing::strip(&mut code).ok()?;
+    let ends : Vec<usize> = (0..code.len()).collect();
+    let end = ends.partition_point(|end| matches!(parse_lines(&code[..=*end]), Ok(_) | Err(Error::EOF(_, _))));
+    lines.get(end).map(whole)
+}
+
+/// `err` as rustc would put it, pointing at the line it's about when that can be told
+fn render(err : &Error, lines : &[Line]) -> String {
+    let Some((line, column, len)) = locate(err, lines) else { return format!("error: {err}") };
+    let number = line.number.to_string();
+    let pad = " ".repeat(number.len());
+    format!(
+        "error: {err}\n{pad}--> {}:{}:{}\n{pad} |\n{number} | {}\n{pad} | {}{}",
+        line.file, line.number, column + 1, line.text.trim_end(), " ".repeat(column), "^".repeat(len),
+    )
+}
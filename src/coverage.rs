@@ -0,0 +1,166 @@
+//! Which source lines and labels a run executed, and where it spent its instructions.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{Decoded, Program, SymbolKind, Token, debug::DebugInfo, utils::Result, vm::{Observer, STACK_TOP, Vm}};
+
+/// Counts the instructions a run executes, watching it as an [`Observer`]
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Times the instruction at each address ran
+    pub hits : BTreeMap<u16, u64>,
+    /// Times each routine was called, and instructions run while it was, calls it made included
+    pub routines : BTreeMap<u16, (u64, u64)>,
+    /// Routines called and not returned from yet, with the stack pointer inside them
+    stack : Vec<(u16, u16)>,
+}
+
+fn percent(part : u64, whole : u64) -> f64 {
+    if whole == 0 { 0. } else { part as f64 * 100. / whole as f64 }
+}
+
+impl Observer for Coverage {
+    fn observe(&mut self, vm : &Vm, decoded : &Decoded) -> Result<()> {
+        let Some(change) = vm.history.last() else { return Ok(()) };
+        *self.hits.entry(change.at()).or_default() += 1;
+
+        // Recursive routines only count once per instruction
+        for (i, (routine, _)) in self.stack.iter().enumerate() {
+            if !self.stack[..i].iter().any(|(other, _)| other == routine) {
+                self.routines.entry(*routine).or_default().1 += 1;
+            }
+        }
+
+        let depth = |sp : u16| STACK_TOP.wrapping_sub(sp);
+        while self.stack.last().is_some_and(|(_, sp)| depth(*sp) > depth(vm.sp)) {
+            self.stack.pop();
+        }
+        if decoded.op == Token::Call {
+            self.stack.push((vm.pc, vm.sp));
+            self.routines.entry(vm.pc).or_default().0 += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Times each line with code on it ran, by file index and line number. A line runs as often
+    /// as the instruction on it that ran most.
+    pub fn lines(&self, info : &DebugInfo) -> BTreeMap<(usize, usize), u64> {
+        let mut res = BTreeMap::new();
+        for entry in info.lines.iter() {
+            let hits = self.hits.get(&entry.address).copied().unwrap_or(0);
+            let line = res.entry((entry.file, entry.line)).or_insert(0);
+            *line = hits.max(*line);
+        }
+        res
+    }
+
+    /// Times execution reached every label
+    pub fn labels<'a>(&self, info : &'a DebugInfo) -> Vec<(&'a str, u64)> {
+        info.symbols.iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Label)
+            .map(|symbol| (symbol.name.as_str(), self.hits.get(&symbol.address).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    /// Every source line with the times it ran, `#####` marking lines with code that never did,
+    /// followed by the labels and a summary
+    pub fn report(&self, program : &Program, info : &DebugInfo) -> String {
+        let lines = self.lines(info);
+        let mut res = String::new();
+        let mut file = None;
+        for line in program.lines.iter() {
+            if file != Some(&line.file) {
+                writeln!(res, "; {}", line.file).unwrap();
+                file = Some(&line.file);
+            }
+            let index = info.files.iter().position(|file| *file == line.file);
+            let count = match index.and_then(|index| lines.get(&(index, line.number))) {
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(res, "{count:>9}  {:>5}  {}", line.number, line.text.trim_end()).unwrap();
+        }
+
+        writeln!(res, "\n; Labels").unwrap();
+        for (name, hits) in self.labels(info) {
+            let hits = if hits == 0 { "#####".to_string() } else { hits.to_string() };
+            writeln!(res, "{hits:>9}  {name}").unwrap();
+        }
+
+        let executed = lines.values().filter(|count| **count > 0).count() as u64;
+        writeln!(res, "\n{executed} of {} lines executed ({:.1}%)", lines.len(), percent(executed, lines.len() as u64)).unwrap();
+        res
+    }
+
+    /// The line and label counts in lcov's tracefile format
+    pub fn lcov(&self, info : &DebugInfo) -> String {
+        let lines = self.lines(info);
+        let mut res = String::from("TN:\n");
+        for (index, file) in info.files.iter().enumerate() {
+            writeln!(res, "SF:{file}").unwrap();
+
+            let functions : Vec<(usize, &str, u64)> = info.symbols.iter()
+                .filter(|symbol| symbol.kind == SymbolKind::Label)
+                .filter_map(|symbol| {
+                    let entry = info.line_at(symbol.address).filter(|entry| entry.file == index)?;
+                    Some((entry.line, symbol.name.as_str(), self.hits.get(&symbol.address).copied().unwrap_or(0)))
+                })
+                .collect();
+            for (line, name, _) in functions.iter() {
+                writeln!(res, "FN:{line},{name}").unwrap();
+            }
+            for (_, name, hits) in functions.iter() {
+                writeln!(res, "FNDA:{hits},{name}").unwrap();
+            }
+            writeln!(res, "FNF:{}\nFNH:{}", functions.len(), functions.iter().filter(|(_, _, hits)| *hits > 0).count()).unwrap();
+
+            let file_lines : Vec<(usize, u64)> = lines.iter()
+                .filter(|((file, _), _)| *file == index)
+                .map(|((_, line), count)| (*line, *count))
+                .collect();
+            for (line, count) in file_lines.iter() {
+                writeln!(res, "DA:{line},{count}").unwrap();
+            }
+            writeln!(res, "LF:{}\nLH:{}", file_lines.len(), file_lines.iter().filter(|(_, count)| *count > 0).count()).unwrap();
+            writeln!(res, "end_of_record").unwrap();
+        }
+        res
+    }
+
+    /// Labels ranked by the instructions run after them before the next label, with the calls
+    /// made to them and the instructions run in those calls
+    pub fn profile(&self, info : &DebugInfo) -> String {
+        let mut own : BTreeMap<u16, u64> = BTreeMap::new();
+        let mut total = 0;
+        for (address, hits) in self.hits.iter() {
+            if let Some(label) = info.label_before(*address) {
+                *own.entry(label.address).or_default() += hits;
+            }
+            total += hits;
+        }
+
+        let mut rows : Vec<(u64, u64, u64, &str)> = info.symbols.iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Label)
+            .filter_map(|symbol| {
+                let own = own.get(&symbol.address).copied().unwrap_or(0);
+                let (calls, inclusive) = self.routines.get(&symbol.address).copied().unwrap_or((0, 0));
+                (own > 0 || calls > 0).then_some((own, inclusive, calls, symbol.name.as_str()))
+            })
+            .collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)));
+
+        let mut res = format!("{:>10}  {:>6}  {:>10}  {:>6}  name\n", "self", "%", "in calls", "calls");
+        for (own, inclusive, calls, name) in rows {
+            writeln!(res, "{own:>10}  {:>5.1}%  {inclusive:>10}  {calls:>6}  {name}", percent(own, total)).unwrap();
+        }
+        writeln!(res, "{total:>10}  instructions").unwrap();
+        res
+    }
+}
@@ -0,0 +1,327 @@
+//! A Debug Adapter Protocol server, so editors can drive the emulator.
+//!
+//! Messages go over any reader and writer, usually stdin and stdout. Since stdin carries the
+//! protocol the program gets no input device, and its console output is sent as output events.
+
+use std::{collections::BTreeMap, io::{BufRead, Read, Write}, path::Path, sync::{Arc, Mutex}};
+
+use crate::{
+    SymbolKind, assemble_file,
+    debugger::{Debugger, Event, HISTORY},
+    json::Json,
+    utils::{self, Error, Result},
+    vm::{Stop, Vm, device},
+};
+
+const REGISTERS : i64 = 1;
+const MEMORY : i64 = 2;
+
+/// Reads one message, `None` once the input's ended
+pub fn read_message(reader : &mut impl BufRead) -> Result<Option<Json>> {
+    let io = |err : std::io::Error| Error::External(err.to_string());
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(io)? == 0 {
+            return Ok(None)
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = value.trim().parse().ok();
+        }
+    }
+
+    let len : usize = len.ok_or_else(|| Error::External("message without a Content-Length".to_string()))?;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).map_err(io)?;
+    Json::parse(&String::from_utf8_lossy(&body)).map(Some)
+}
+
+pub struct Adapter<W : Write> {
+    writer : W,
+    seq : i64,
+    dbg : Option<Debugger>,
+    output : Arc<Mutex<Vec<u8>>>,
+    stop_on_entry : bool,
+    /// Breakpoint addresses by source path
+    sources : BTreeMap<String, Vec<u16>>,
+}
+
+fn hex(value : u16) -> Json {
+    Json::from(format!("{value:#06X}"))
+}
+
+impl<W : Write> Adapter<W> {
+    pub fn new(writer : W) -> Self {
+        Self { writer, seq: 1, dbg: None, output: Arc::default(), stop_on_entry: false, sources: BTreeMap::new() }
+    }
+
+    fn send(&mut self, mut fields : Vec<(String, Json)>) -> Result<()> {
+        fields.insert(0, ("seq".to_string(), Json::from(self.seq as u64)));
+        self.seq += 1;
+        let body = Json::Object(fields).to_string();
+        write!(self.writer, "Content-Length: {}\r\n\r\n{body}", body.len())
+            .and_then(|_| self.writer.flush())
+            .map_err(|err| Error::External(err.to_string()))
+    }
+
+    fn respond(&mut self, request : &Json, body : Result<Json>) -> Result<()> {
+        let (success, body) = match body {
+            Ok(body) => (true, ("body".to_string(), body)),
+            Err(err) => (false, ("message".to_string(), Json::from(err.to_string()))),
+        };
+        self.send(vec![
+            ("type".to_string(), Json::from("response")),
+            ("request_seq".to_string(), request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success".to_string(), Json::from(success)),
+            ("command".to_string(), request.get("command").cloned().unwrap_or(Json::Null)),
+            body,
+        ])
+    }
+
+    fn event(&mut self, event : &str, body : Json) -> Result<()> {
+        self.send(vec![
+            ("type".to_string(), Json::from("event")),
+            ("event".to_string(), Json::from(event)),
+            ("body".to_string(), body),
+        ])
+    }
+
+    fn dbg(&mut self) -> Result<&mut Debugger> {
+        self.dbg.as_mut().ok_or_else(|| Error::External("no program launched".to_string()))
+    }
+
+    fn launch(&mut self, args : &Json) -> Result<Json> {
+        let path = args.get("program").and_then(Json::as_str)
+            .ok_or_else(|| Error::External("launch needs a program".to_string()))?;
+        let program = assemble_file(path)?;
+        let mut vm = Vm::from_program(&program);
+        device::attach_default(&mut vm, self.output.clone())?;
+        vm.history.capacity = HISTORY;
+        self.dbg = Some(Debugger::new(program, vm));
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+        Ok(Json::Null)
+    }
+
+    fn set_breakpoints(&mut self, args : &Json) -> Result<Json> {
+        let path = args.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).unwrap_or("").to_string();
+        let lines : Vec<i64> = args.get("breakpoints").and_then(Json::as_array).unwrap_or(&[]).iter()
+            .filter_map(|bp| bp.get("line").and_then(Json::as_i64))
+            .collect();
+
+        let dbg = self.dbg()?;
+        let source = Path::new(&path);
+        let matches = |file : &String| source.ends_with(file) || Path::new(file).ends_with(source);
+        // An empty path would end every file's
+        let file = if path.is_empty() { None } else { dbg.info.files.iter().position(matches) };
+        let mut addresses = Vec::new();
+        let mut breakpoints = Vec::new();
+        for line in lines.into_iter() {
+            let at : Vec<u16> = dbg.info.lines.iter()
+                .filter(|entry| Some(entry.file) == file && entry.line as i64 == line)
+                .map(|entry| entry.address)
+                .take(1)
+                .collect();
+            breakpoints.push(Json::object([("verified", Json::from(!at.is_empty())), ("line", Json::from(line))]));
+            addresses.extend(at);
+        }
+
+        self.sources.insert(path, addresses);
+        let all = self.sources.values().flatten().copied().collect();
+        self.dbg()?.breakpoints = all;
+        Ok(Json::object([("breakpoints", Json::from(breakpoints))]))
+    }
+
+    fn stack_trace(&mut self) -> Result<Json> {
+        let dbg = self.dbg()?;
+        let addresses : Vec<u16> = [dbg.vm.pc].into_iter().chain(dbg.frames.iter().rev().map(|frame| frame.call)).collect();
+        let frames : Vec<Json> = addresses.iter().enumerate().map(|(id, address)| {
+            let name = dbg.routine_of(*address).map(str::to_string).unwrap_or_else(|| format!("{address:#06X}"));
+            let mut frame = vec![
+                ("id".to_string(), Json::from(id)),
+                ("name".to_string(), Json::from(name)),
+                ("instructionPointerReference".to_string(), hex(*address)),
+            ];
+            let (line, column) = match dbg.info.line_at(*address) {
+                Some(entry) => {
+                    let path = &dbg.info.files[entry.file];
+                    frame.push(("source".to_string(), Json::object([("path", Json::from(path.as_str()))])));
+                    (entry.line, entry.column)
+                },
+                None => (0, 0),
+            };
+            frame.push(("line".to_string(), Json::from(line)));
+            frame.push(("column".to_string(), Json::from(column)));
+            Json::Object(frame)
+        }).collect();
+        Ok(Json::object([("totalFrames", Json::from(frames.len())), ("stackFrames", Json::from(frames))]))
+    }
+
+    fn variables(&mut self, reference : i64) -> Result<Json> {
+        let dbg = self.dbg()?;
+        let variable = |name : &str, value : String| Json::object([
+            ("name", Json::from(name)), ("value", Json::from(value)), ("variablesReference", Json::from(0u8)),
+        ]);
+
+        let variables : Vec<Json> = match reference {
+            REGISTERS => {
+                let flags = dbg.vm.flag_names();
+                (0..16).map(|i| variable(&format!("r{i}"), format!("{:#06X}", dbg.vm.registers[i])))
+                    .chain([
+                        variable("sp", format!("{:#06X}", dbg.vm.sp)),
+                        variable("pc", format!("{:#06X}", dbg.vm.pc)),
+                        variable("flags", flags),
+                    ])
+                    .collect()
+            },
+            MEMORY => dbg.info.symbols.iter()
+                .filter(|symbol| symbol.kind != SymbolKind::Constant)
+                .map(|symbol| {
+                    let len = symbol.size.unwrap_or(16).min(16);
+                    let bytes : Vec<String> = (0..len)
+                        .map(|i| format!("{:02X}", dbg.vm.memory[symbol.address.wrapping_add(i) as usize]))
+                        .collect();
+                    variable(&symbol.name, format!("{:#06X}: {}", symbol.address, bytes.join(" ")))
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Ok(Json::object([("variables", Json::from(variables))]))
+    }
+
+    fn evaluate(&mut self, expression : &str) -> Result<Json> {
+        let dbg = self.dbg()?;
+        let expression = expression.trim();
+        let result = if let Some((_, reg)) = utils::registers().iter().find(|(name, _)| name == expression) {
+            format!("{:#06X}", dbg.vm.reg(*reg)?)
+        } else if expression == "pc" || expression == "sp" {
+            format!("{:#06X}", if expression == "pc" { dbg.vm.pc } else { dbg.vm.sp })
+        } else if let Some(symbol) = dbg.info.symbol(expression) {
+            match symbol.kind {
+                SymbolKind::Constant => format!("{:#06X}", symbol.address),
+                _ => {
+                    let word = [0, 1].map(|i| dbg.vm.memory[symbol.address.wrapping_add(i) as usize]);
+                    format!("{:#06X}, [{:#06X}] = {:#06X}", symbol.address, symbol.address, u16::from_le_bytes(word))
+                },
+            }
+        } else {
+            return Err(Error::NoSuchIdentifier(expression.to_string()))
+        };
+        Ok(Json::object([("result", Json::from(result)), ("variablesReference", Json::from(0u8))]))
+    }
+
+    /// Tells the client how a run ended
+    fn report(&mut self, event : Result<Event>) -> Result<()> {
+        let output = std::mem::take(&mut *self.output.lock().unwrap());
+        if !output.is_empty() {
+            let output = String::from_utf8_lossy(&output).to_string();
+            self.event("output", Json::object([("category", Json::from("stdout")), ("output", Json::from(output))]))?;
+        }
+
+        let (reason, description) = match event {
+            Ok(Event::Step) => ("step", None),
+            Ok(Event::Breakpoint(_)) => ("breakpoint", None),
+            Ok(Event::HistoryStart) => ("step", Some("reached the oldest instruction kept".to_string())),
+            Ok(Event::Watchpoint(address, old, new)) =>
+                ("data breakpoint", Some(format!("{address:#06X} changed from {old:#04X} to {new:#04X}"))),
+            Ok(Event::Stopped(Stop::CycleLimit)) => ("pause", Some("cycle limit reached".to_string())),
+            Ok(Event::Stopped(stop)) => {
+                let code = if let Stop::Exit(code) = stop { code } else { 0 };
+                self.event("exited", Json::object([("exitCode", Json::from(code))]))?;
+                return self.event("terminated", Json::object([]))
+            },
+            Err(err) => ("exception", Some(err.to_string())),
+        };
+
+        let mut body = vec![
+            ("reason".to_string(), Json::from(reason)),
+            ("threadId".to_string(), Json::from(1u8)),
+            ("allThreadsStopped".to_string(), Json::from(true)),
+        ];
+        if let Some(description) = description {
+            body.push(("text".to_string(), Json::from(description)));
+        }
+        self.event("stopped", Json::Object(body))
+    }
+
+    /// Handles one request, returning whether to keep going
+    pub fn handle(&mut self, request : &Json) -> Result<bool> {
+        let args = request.get("arguments").cloned().unwrap_or(Json::Object(vec![]));
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+
+        // Running requests are answered straight away, and the stop reported as an event after
+        let run : Option<fn(&mut Debugger) -> Result<Event>> = match command {
+            "continue" => Some(Debugger::cont),
+            "next" => Some(Debugger::next),
+            "stepIn" => Some(Debugger::step),
+            "stepOut" => Some(Debugger::finish),
+            "stepBack" => Some(Debugger::reverse_step),
+            "reverseContinue" => Some(Debugger::reverse_cont),
+            _ => None,
+        };
+        if let Some(run) = run {
+            let body = if command == "continue" { Json::object([("allThreadsContinued", Json::from(true))]) } else { Json::Null };
+            let body = self.dbg().map(|_| body);
+            let launched = body.is_ok();
+            self.respond(request, body)?;
+            if launched {
+                let event = run(self.dbg()?);
+                self.report(event)?;
+            }
+            return Ok(true)
+        }
+
+        let body = match command {
+            "initialize" => Ok(Json::object([
+                ("supportsConfigurationDoneRequest", Json::from(true)),
+                ("supportsEvaluateForHovers", Json::from(true)),
+                ("supportsStepBack", Json::from(true)),
+            ])),
+            "launch" => self.launch(&args),
+            "setBreakpoints" => self.set_breakpoints(&args),
+            "setExceptionBreakpoints" => Ok(Json::object([("breakpoints", Json::from(vec![]))])),
+            "threads" => Ok(Json::object([("threads", Json::from(vec![Json::object([("id", Json::from(1u8)), ("name", Json::from("main"))])]))])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object([("scopes", Json::from(vec![
+                Json::object([("name", Json::from("Registers")), ("variablesReference", Json::from(REGISTERS)), ("expensive", Json::from(false))]),
+                Json::object([("name", Json::from("Memory")), ("variablesReference", Json::from(MEMORY)), ("expensive", Json::from(false))]),
+            ]))])),
+            "variables" => self.variables(args.get("variablesReference").and_then(Json::as_i64).unwrap_or(0)),
+            "evaluate" => self.evaluate(args.get("expression").and_then(Json::as_str).unwrap_or("")),
+            // Requests are answered one at a time, so there's never a run going on to pause
+            "pause" => Err(Error::External("pause isn't supported, the program runs until it stops".to_string())),
+            "configurationDone" | "disconnect" => Ok(Json::Null),
+            _ => Err(Error::External(format!("{command} isn't supported"))),
+        };
+        let launched = command == "launch" && body.is_ok();
+        self.respond(request, body)?;
+
+        match command {
+            "launch" if launched => self.event("initialized", Json::Null)?,
+            "configurationDone" if self.dbg.is_some() && self.stop_on_entry => self.event("stopped", Json::object([
+                ("reason", Json::from("entry")), ("threadId", Json::from(1u8)), ("allThreadsStopped", Json::from(true)),
+            ]))?,
+            "configurationDone" if self.dbg.is_some() => {
+                let event = self.dbg()?.cont();
+                self.report(event)?;
+            },
+            "disconnect" => return Ok(false),
+            _ => (),
+        }
+        Ok(true)
+    }
+}
+
+/// Serves requests from `reader` until the client disconnects
+pub fn serve(mut reader : impl BufRead, writer : impl Write) -> Result<()> {
+    let mut adapter = Adapter::new(writer);
+    while let Some(request) = read_message(&mut reader)? {
+        if !adapter.handle(&request)? {
+            break
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! Debug information mapping addresses back to source.
+//!
+//! The text format is line oriented, with space separated fields and addresses, lengths and sizes in
+//! hex. It starts with a `SASM-DEBUG <version>` header, followed by records in any order:
+//!
+//! - `F <id> <path>`: a source file, paths run to the end of the line
+//! - `L <addr> <len> <file> <line> <column>`: an instruction, and where it was written
+//! - `E <addr> <len> <file> <line> <column> <text>`: an instruction expanded from the line at
+//!   `<file>:<line>`, `<text>` being what it expanded to
+//! - `S <addr> <name> <kind> <size> <section>`: a symbol, in the same format as map files
+//!
+//! Lines and columns start at 1. Readers should skip records they don't know.
+
+use std::{fmt::{self, Display, Write}, path::Path};
+
+use crate::{Expr, Program, Symbol, SymbolKind, utils::{Error, Result}};
+
+pub const VERSION : u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEntry {
+    pub address : u16,
+    pub len : u16,
+    pub file : usize,
+    pub line : usize,
+    pub column : usize,
+    pub expansion : Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub files : Vec<String>,
+    pub lines : Vec<LineEntry>,
+    pub symbols : Vec<Symbol>,
+}
+
+/// Column the statement after any leading labels starts at
+fn column(code : &str, is_label : bool) -> usize {
+    let mut rest = code.trim_start();
+    if !is_label {
+        while let Some((label, after)) = rest.split_once(':') {
+            if label.is_empty() || !label.trim().chars().all(|c| c.is_alphanumeric() || c == '_') {
+                break
+            }
+            rest = after.trim_start();
+        }
+    }
+    code.len() - rest.len() + 1
+}
+
+impl DebugInfo {
+    pub fn new(program : &Program) -> Self {
+        let mut info = Self { symbols: program.symbols.clone(), ..Default::default() };
+
+        for stmt in program.statements.iter() {
+            let line = &program.lines[stmt.line];
+            let file = match info.files.iter().position(|file| *file == line.file) {
+                Some(file) => file,
+                None => {
+                    info.files.push(line.file.clone());
+                    info.files.len() - 1
+                },
+            };
+            let column = column(&line.code, matches!(stmt.expr, Expr::IdentifierDef(_)));
+
+            let mut entry = |address : u16, len : u16, expansion : Option<&str>| info.lines.push(LineEntry {
+                address, len, file, line: line.number, column, expansion: expansion.map(str::to_string),
+            });
+
+            let mut address = stmt.address;
+            match &stmt.expr {
+                Expr::Expansion(exprs) => for (text, expr) in exprs.iter() {
+                    if expr.len() > 0 {
+                        entry(address, expr.len(), Some(text));
+                    }
+                    address = address.wrapping_add(expr.len());
+                },
+                _ => for inst in stmt.instructions.iter() {
+                    entry(address, inst.len(), None);
+                    address = address.wrapping_add(inst.len());
+                },
+            }
+        }
+
+        info
+    }
+
+    /// The instruction covering `address`
+    pub fn line_at(&self, address : u16) -> Option<&LineEntry> {
+        self.lines.iter().find(|entry| entry.address <= address && address < entry.address.wrapping_add(entry.len))
+    }
+
+    /// Addresses of the instructions written on `file:line`, `file` being the last components of a path
+    pub fn addresses_of(&self, file : &str, line : usize) -> Vec<u16> {
+        self.lines.iter()
+            .filter(|entry| entry.line == line && Path::new(&self.files[entry.file]).ends_with(file))
+            .map(|entry| entry.address)
+            .collect()
+    }
+
+    pub fn symbol(&self, name : &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The last label at or before `address`
+    pub fn label_before(&self, address : u16) -> Option<&Symbol> {
+        self.symbols.iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Label && symbol.address <= address)
+            .max_by_key(|symbol| symbol.address)
+    }
+
+    pub fn parse(text : &str) -> Result<Self> {
+        let malformed = |line : &str| Error::External(format!("malformed debug info: {line}"));
+        let hex = |field : Option<&str>, line : &str| -> Result<u16> {
+            field.and_then(|field| u16::from_str_radix(field, 16).ok()).ok_or_else(|| malformed(line))
+        };
+        let dec = |field : Option<&str>, line : &str| -> Result<usize> {
+            field.and_then(|field| field.parse().ok()).ok_or_else(|| malformed(line))
+        };
+
+        let mut lines = text.lines();
+        match lines.next().and_then(|header| header.strip_prefix("SASM-DEBUG ")) {
+            Some(version) if version.trim() == VERSION.to_string() => (),
+            _ => return Err(Error::External("not a version 1 sasm debug info file".to_string())),
+        }
+
+        let mut info = Self::default();
+        for line in lines {
+            let mut fields = line.splitn(7, ' ');
+            match fields.next() {
+                Some("F") => {
+                    let id = dec(fields.next(), line)?;
+                    let path = line.splitn(3, ' ').nth(2).ok_or_else(|| malformed(line))?;
+                    if id != info.files.len() {
+                        return Err(malformed(line))
+                    }
+                    info.files.push(path.to_string());
+                },
+                Some(kind @ ("L" | "E")) => {
+                    let entry = LineEntry {
+                        address: hex(fields.next(), line)?,
+                        len: hex(fields.next(), line)?,
+                        file: dec(fields.next(), line)?,
+                        line: dec(fields.next(), line)?,
+                        column: dec(fields.next(), line)?,
+                        expansion: if kind == "E" { Some(fields.next().ok_or_else(|| malformed(line))?.to_string()) } else { None },
+                    };
+                    if entry.file >= info.files.len() {
+                        return Err(malformed(line))
+                    }
+                    info.lines.push(entry);
+                },
+                Some("S") => {
+                    let address = hex(fields.next(), line)?;
+                    let name = fields.next().ok_or_else(|| malformed(line))?;
+                    let kind = fields.next().and_then(SymbolKind::from_name).ok_or_else(|| malformed(line))?;
+                    let size = match fields.next() {
+                        Some("-") => None,
+                        size => Some(hex(size, line)?),
+                    };
+                    let section = match fields.next() {
+                        Some("-") => None,
+                        Some(section) => Some(section.to_string()),
+                        None => return Err(malformed(line)),
+                    };
+                    info.symbols.push(Symbol { name: name.to_string(), address, kind, size, section });
+                },
+                _ => (),
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+impl Display for DebugInfo {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "SASM-DEBUG {VERSION}")?;
+        for (id, file) in self.files.iter().enumerate() {
+            writeln!(f, "F {id} {file}")?;
+        }
+        for entry in self.lines.iter() {
+            let mut record = format!("{:04X} {:04X} {} {} {}", entry.address, entry.len, entry.file, entry.line, entry.column);
+            match &entry.expansion {
+                Some(text) => { write!(record, " {text}")?; writeln!(f, "E {record}")? },
+                None => writeln!(f, "L {record}")?,
+            }
+        }
+        for line in crate::map::map_symbols(&self.symbols).lines() {
+            writeln!(f, "S {line}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+//! A source level debugger driving the emulator.
+
+use std::{collections::{BTreeMap, BTreeSet, VecDeque}, io::{BufRead, Write}};
+
+use crate::{Program, Token, debug::DebugInfo, format::embed::hexdump, utils::{Error, Result}, vm::{Stop, Vm}};
+
+/// Why the debugger handed control back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The step asked for is done
+    Step,
+    Breakpoint(u16),
+    /// A watched byte changed, from the first value to the second
+    Watchpoint(u16, u8, u8),
+    Stopped(Stop),
+    /// Running backwards reached the oldest instruction the history has
+    HistoryStart,
+}
+
+/// A call the machine is in the middle of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the `call`
+    pub call : u16,
+    /// Address it called
+    pub routine : u16,
+    sp : u16,
+    /// Instructions run once the call was made
+    cycle : u64,
+}
+
+/// Instructions the debugger keeps to run backwards through, unless told otherwise
+pub const HISTORY : usize = 100_000;
+
+pub struct Debugger {
+    pub vm : Vm,
+    pub program : Program,
+    pub info : DebugInfo,
+    pub breakpoints : BTreeSet<u16>,
+    /// Watched bytes, with the value they had last time they were looked at
+    pub watchpoints : BTreeMap<u16, u8>,
+    /// Instructions to run before giving up on a `continue`
+    pub max_cycles : u64,
+    /// Calls made since the debugger started and not returned from yet, innermost last
+    pub frames : Vec<Frame>,
+    /// Frames that have been returned from, with the cycle they were, to bring back running backwards
+    returned : VecDeque<(u64, Frame)>,
+}
+
+fn parse_number(arg : &str) -> Option<u16> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+impl Debugger {
+    /// A debugger for `vm`, which can run backwards as far as the vm's history goes
+    pub fn new(program : Program, vm : Vm) -> Self {
+        let info = DebugInfo::new(&program);
+        Self {
+            vm, program, info,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            max_cycles: 1_000_000,
+            frames: Vec::new(),
+            returned: VecDeque::new(),
+        }
+    }
+
+    /// Addresses a symbol name, `file:line` or plain address stands for
+    pub fn resolve(&self, location : &str) -> Result<Vec<u16>> {
+        if let Some(symbol) = self.info.symbol(location) {
+            return Ok(vec![symbol.address])
+        }
+        if let Some(address) = parse_number(location) {
+            return Ok(vec![address])
+        }
+        if let Some((file, line)) = location.rsplit_once(':') {
+            let addresses = line.parse().map(|line| self.info.addresses_of(file, line)).unwrap_or_default();
+            if !addresses.is_empty() {
+                return Ok(addresses)
+            }
+        }
+        Err(Error::External(format!("no code at {location}")))
+    }
+
+    /// Where `address` was written, as `file:line  source`
+    pub fn location(&self, address : u16) -> Option<String> {
+        let entry = self.info.line_at(address)?;
+        let file = &self.info.files[entry.file];
+        let text = self.program.lines.iter()
+            .find(|line| line.file == *file && line.number == entry.line)
+            .map(|line| line.text.trim())
+            .unwrap_or("");
+        Some(match &entry.expansion {
+            Some(expansion) => format!("{file}:{}  {text}  ({expansion})", entry.line),
+            None => format!("{file}:{}  {text}", entry.line),
+        })
+    }
+
+    fn watched(&mut self) -> Option<Event> {
+        for (address, old) in self.watchpoints.iter_mut() {
+            let new = self.vm.memory[*address as usize];
+            if new != *old {
+                let event = Event::Watchpoint(*address, *old, new);
+                *old = new;
+                return Some(event)
+            }
+        }
+        None
+    }
+
+    fn track_frames(&mut self, pc : u16, is_call : bool) {
+        let depth = |sp : u16| crate::vm::STACK_TOP.wrapping_sub(sp);
+        while self.frames.last().is_some_and(|frame| depth(frame.sp) > depth(self.vm.sp)) {
+            self.returned.extend(self.frames.pop().map(|frame| (self.vm.cycles, frame)));
+        }
+        while self.returned.len() > self.vm.history.capacity {
+            self.returned.pop_front();
+        }
+        if is_call {
+            self.frames.push(Frame { call: pc, routine: self.vm.pc, sp: self.vm.sp, cycle: self.vm.cycles });
+        }
+    }
+
+    /// Puts the frames back the way they were before the instructions just undone
+    fn untrack_frames(&mut self) {
+        let cycles = self.vm.cycles;
+        while self.frames.last().is_some_and(|frame| frame.cycle > cycles) {
+            self.frames.pop();
+        }
+        while self.returned.back().is_some_and(|(cycle, _)| *cycle > cycles) {
+            let (_, frame) = self.returned.pop_back().unwrap();
+            if frame.cycle <= cycles {
+                self.frames.push(frame);
+            }
+        }
+    }
+
+    /// The label `address` comes after
+    pub fn routine_of(&self, address : u16) -> Option<&str> {
+        self.info.label_before(address).map(|symbol| symbol.name.as_str())
+    }
+
+    /// Runs until `done` holds after an instruction, or something else stops the machine
+    fn run_until(&mut self, mut done : impl FnMut(&Vm) -> bool) -> Result<Event> {
+        let limit = self.vm.cycles + self.max_cycles;
+        loop {
+            if self.vm.cycles >= limit {
+                return Ok(Event::Stopped(Stop::CycleLimit))
+            }
+            let (pc, is_call) = (self.vm.pc, self.vm.fetch(self.vm.pc).is_ok_and(|decoded| decoded.op == Token::Call));
+            if let Some(stop) = self.vm.step()? {
+                return Ok(Event::Stopped(stop))
+            }
+            self.track_frames(pc, is_call);
+            if let Some(event) = self.watched() {
+                return Ok(event)
+            }
+            if done(&self.vm) {
+                return Ok(Event::Step)
+            }
+            if self.breakpoints.contains(&self.vm.pc) {
+                return Ok(Event::Breakpoint(self.vm.pc))
+            }
+        }
+    }
+
+    /// Runs a single instruction
+    pub fn step(&mut self) -> Result<Event> {
+        self.run_until(|_| true)
+    }
+
+    /// Runs a single instruction, running calls through to their return
+    pub fn next(&mut self) -> Result<Event> {
+        let decoded = self.vm.fetch(self.vm.pc)?;
+        if decoded.op != Token::Call {
+            return self.step()
+        }
+        let (next, sp) = (self.vm.pc.wrapping_add(decoded.len()), self.vm.sp);
+        self.run_until(|vm| vm.pc == next && vm.sp == sp)
+    }
+
+    /// Runs until the current routine returns
+    pub fn finish(&mut self) -> Result<Event> {
+        let depth = |vm : &Vm| crate::vm::STACK_TOP.wrapping_sub(vm.sp);
+        let start = depth(&self.vm);
+        self.run_until(|vm| depth(vm) < start)
+    }
+
+    pub fn cont(&mut self) -> Result<Event> {
+        self.run_until(|_| false)
+    }
+
+    /// Undoes instructions until `done` holds, or something else stops it
+    fn run_back(&mut self, mut done : impl FnMut(&Vm) -> bool) -> Result<Event> {
+        loop {
+            if self.vm.undo().is_none() {
+                return Ok(Event::HistoryStart)
+            }
+            self.untrack_frames();
+            if let Some(event) = self.watched() {
+                return Ok(event)
+            }
+            if done(&self.vm) {
+                return Ok(Event::Step)
+            }
+            if self.breakpoints.contains(&self.vm.pc) {
+                return Ok(Event::Breakpoint(self.vm.pc))
+            }
+        }
+    }
+
+    /// Undoes the last instruction
+    pub fn reverse_step(&mut self) -> Result<Event> {
+        self.run_back(|_| true)
+    }
+
+    /// Runs backwards to the last breakpoint or watchpoint hit
+    pub fn reverse_cont(&mut self) -> Result<Event> {
+        self.run_back(|_| false)
+    }
+
+    /// Describes an event, and where the machine is now
+    pub fn describe(&mut self, event : &Event) -> String {
+        let mut res = match event {
+            Event::Step => String::new(),
+            Event::Breakpoint(address) => format!("breakpoint at {address:#06X}\n"),
+            Event::Watchpoint(address, old, new) => format!("{address:#06X} changed from {old:#04X} to {new:#04X}\n"),
+            Event::Stopped(stop) => format!("stopped: {stop:?}\n"),
+            Event::HistoryStart => "no more history to run back through\n".to_string(),
+        };
+        if let Some(location) = self.location(self.vm.pc) {
+            res += &format!("{location}\n");
+        }
+        match self.vm.fetch(self.vm.pc) {
+            Ok(decoded) => res += &format!("=> {:#06X}  {decoded}\n", self.vm.pc),
+            Err(_) => res += &format!("=> {:#06X}  ??\n", self.vm.pc),
+        }
+        res
+    }
+
+    /// Runs one command, returning what to print, or `None` to quit
+    pub fn command(&mut self, line : &str) -> Result<Option<String>> {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        let single = |words : &[&str]| -> Result<u16> {
+            match words {
+                [location] => Ok(self.resolve(location)?[0]),
+                _ => Err(Error::External("expected an address, symbol or file:line".to_string())),
+            }
+        };
+
+        let event = match words[..] {
+            [] | ["s" | "step"] => self.step()?,
+            ["n" | "next"] => self.next()?,
+            ["f" | "finish"] => self.finish()?,
+            ["c" | "continue"] => self.cont()?,
+            ["rs" | "reverse-step"] => self.reverse_step()?,
+            ["rc" | "reverse-continue"] => self.reverse_cont()?,
+            ["b" | "break", location] => {
+                let addresses = self.resolve(location)?;
+                self.breakpoints.extend(addresses.iter().copied());
+                return Ok(Some(addresses.iter().map(|address| format!("breakpoint at {address:#06X}\n")).collect()))
+            },
+            ["d" | "delete", location] => {
+                for address in self.resolve(location)? {
+                    self.breakpoints.remove(&address);
+                }
+                return Ok(Some(String::new()))
+            },
+            ["w" | "watch", location, ..] => {
+                let address = single(&words[1..2])?;
+                let len = words.get(2).and_then(|len| parse_number(len)).unwrap_or(1);
+                for address in (0..len).map(|i| address.wrapping_add(i)) {
+                    self.watchpoints.insert(address, self.vm.memory[address as usize]);
+                }
+                return Ok(Some(format!("watching {len} bytes at {address:#06X} ({location})\n")))
+            },
+            ["r" | "regs"] => return Ok(Some(self.vm.dump())),
+            ["x", location, ..] => {
+                let address = single(&words[1..2])?;
+                let len = words.get(2).and_then(|len| parse_number(len)).unwrap_or(16) as usize;
+                let bytes : Vec<u8> = (0..len).map(|i| self.vm.memory[address.wrapping_add(i as u16) as usize]).collect();
+                return Ok(Some(hexdump(&bytes, address)))
+            },
+            ["l" | "where"] => Event::Step,
+            ["q" | "quit"] => return Ok(None),
+            ["h" | "help"] => return Ok(Some(HELP.to_string())),
+            _ => return Err(Error::External(format!("unknown command {line}, try help"))),
+        };
+        Ok(Some(self.describe(&event)))
+    }
+
+    /// Reads commands from `input` until it ends or says to quit
+    pub fn repl(&mut self, input : impl BufRead, mut output : impl Write) -> Result<()> {
+        let io = |err : std::io::Error| Error::External(err.to_string());
+        write!(output, "{}(sasm) ", self.describe(&Event::Step)).map_err(io)?;
+        output.flush().map_err(io)?;
+        for line in input.lines() {
+            match self.command(&line.map_err(io)?) {
+                Ok(Some(text)) => write!(output, "{text}").map_err(io)?,
+                Ok(None) => break,
+                Err(err) => writeln!(output, "error: {err}").map_err(io)?,
+            }
+            write!(output, "(sasm) ").map_err(io)?;
+            output.flush().map_err(io)?;
+        }
+        Ok(())
+    }
+}
+
+const HELP : &str = "\
+step, s                 run one instruction
+next, n                 run one instruction, running calls through
+finish, f               run until the current routine returns
+continue, c             run until a breakpoint, watchpoint or the end
+reverse-step, rs        undo the last instruction
+reverse-continue, rc    run backwards until a breakpoint, watchpoint or the oldest instruction kept
+break, b LOC            break at a label, file:line or address
+delete, d LOC           remove a breakpoint
+watch, w LOC [N]        stop when any of the N bytes at LOC change
+regs, r                 show the registers
+x LOC [N]               show N bytes of memory at LOC
+where, l                show where the machine is
+quit, q
+";
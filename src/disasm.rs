@@ -0,0 +1,376 @@
+use std::{collections::HashMap, fmt::{self, Display}, sync::OnceLock};
+
+use smpl_core_common::Instruction;
+use smpl_parser::Scanner;
+use crate::{Expr, Token, parser::parse_toks, utils::{self, Result}};
+
+/// An instruction, along with the tokens it's written as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+    pub instruction : Instruction,
+    pub op : Token,
+    pub operands : Vec<Token>,
+}
+
+impl Decoded {
+    pub fn db(value : u8) -> Self {
+        Self { instruction: Instruction::db(value), op: Token::DB, operands: vec![Token::Number(value as i64)] }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u16 {
+        self.instruction.len()
+    }
+}
+
+pub(crate) fn render_operand(operand : &Token) -> String {
+    match operand {
+        Token::Register(reg) => utils::register_name(*reg).to_string(),
+        Token::Pointer(reg) => format!("[{}]", utils::register_name(*reg)),
+        Token::Number(value) if !(0..=0xFF).contains(value) => format!("{value:#06X}"),
+        Token::Number(value) => format!("{value:#04X}"),
+        Token::IdentifierRef(ident) => ident.clone(),
+        _ => format!("{operand:?}"),
+    }
+}
+
+impl Display for Decoded {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.op.mnemonic().unwrap_or("?"))?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, render_operand(operand))?;
+        }
+        Ok(())
+    }
+}
+
+/// An encoding, with the position of the low and high bytes of its immediate if it has one
+struct Form {
+    op : Token,
+    operands : Vec<Token>,
+    instruction : Instruction,
+    template : Vec<u8>,
+    immediate : Option<(usize, Option<usize>)>,
+}
+
+struct Table {
+    forms : Vec<Form>,
+    by_first : HashMap<u8, Vec<usize>>,
+    wildcard : Vec<usize>,
+}
+
+fn compile(instruction : Instruction) -> Vec<u8> {
+    instruction.compile().into_iter().collect()
+}
+
+fn build(op : &Token, operands : &[Token]) -> Option<Instruction> {
+    let mut toks = Vec::new();
+    for operand in operands.iter() {
+        if !toks.is_empty() {
+            toks.push(Token::Comma);
+        }
+        toks.push(operand.clone());
+    }
+
+    match parse_toks(op.clone(), &mut Scanner::new(toks.into())) {
+        Ok(Expr::Instruction(instruction)) => Some(instruction),
+        _ => None,
+    }
+}
+
+/// Works out an encoding by assembling it, comparing different immediates to find where they go
+fn forms(op : Token, operands : Vec<Token>) -> Vec<Form> {
+    let Some(at) = operands.iter().position(|operand| matches!(operand, Token::Number(_))) else {
+        return build(&op, &operands).map(|instruction| Form {
+            template: compile(instruction), op, operands, instruction, immediate: None,
+        }).into_iter().collect()
+    };
+
+    let with = |value : i64| -> Option<(Vec<Token>, Instruction)> {
+        let mut operands = operands.clone();
+        operands[at] = Token::Number(value);
+        build(&op, &operands).map(|instruction| (operands, instruction))
+    };
+    let word = match operands.iter().find(|operand| matches!(operand, Token::Register(_))) {
+        Some(Token::Register(reg)) => utils::register_index(*reg).is_ok_and(|(_, byte)| !byte),
+        _ => true,
+    };
+
+    let clean = with(1).and_then(|(operands, instruction)| {
+        let base = compile(instruction);
+        let diff = |value : i64| -> Option<usize> {
+            let other = compile(with(value)?.1);
+            let diff : Vec<usize> = (0..base.len()).filter(|i| other.len() == base.len() && base[*i] != other[*i]).collect();
+            match diff[..] {
+                [i] => Some(i),
+                _ => None,
+            }
+        };
+
+        let lo = diff(2)?;
+        let hi = if word { Some(diff(0x0201)?) } else { None };
+        let mut template = base.clone();
+        template[lo] = 0;
+        if let Some(hi) = hi {
+            template[hi] = 0;
+        }
+        Some(Form { op: op.clone(), operands, instruction, template, immediate: Some((lo, hi)) })
+    });
+
+    match clean {
+        Some(form) => vec![form],
+        // The immediate isn't a plain byte or word, try every byte instead
+        None => (0..=0xFF).filter_map(with).map(|(operands, instruction)| Form {
+            op: op.clone(), operands, instruction, template: compile(instruction), immediate: None,
+        }).collect(),
+    }
+}
+
+fn table() -> &'static Table {
+    static TABLE : OnceLock<Table> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        use Token::*;
+        let regs : Vec<Token> = utils::registers().iter().map(|(_, reg)| Register(*reg)).collect();
+        let ptrs : Vec<Token> = utils::registers().iter().map(|(_, reg)| Pointer(*reg)).collect();
+        let mut forms = Vec::new();
+
+        for op in [Nop, Ret, Cli] {
+            forms.append(&mut self::forms(op, vec![]));
+        }
+        for op in [Push, Pop, Not, AJmp, Jmp, Jeq, Jneq, Jlt, Jgt, Jleq, Jgeq, Jo, Jno, Call, Int, Sti] {
+            for reg in regs.iter() {
+                forms.append(&mut self::forms(op.clone(), vec![reg.clone()]));
+            }
+        }
+        forms.append(&mut self::forms(Call, vec![Number(0)]));
+        for op in [Mov, Add, Sub, And, Or, Cmp] {
+            for r1 in regs.iter() {
+                for r2 in regs.iter() {
+                    forms.append(&mut self::forms(op.clone(), vec![r1.clone(), r2.clone()]));
+                }
+            }
+        }
+        for reg in regs.iter() {
+            for ptr in ptrs.iter() {
+                forms.append(&mut self::forms(Mov, vec![reg.clone(), ptr.clone()]));
+                forms.append(&mut self::forms(Mov, vec![ptr.clone(), reg.clone()]));
+            }
+        }
+        for op in [Mov, Add, Sub, And, Or, Cmp, Shl, Shr, Shre] {
+            for reg in regs.iter() {
+                forms.append(&mut self::forms(op.clone(), vec![Number(0), reg.clone()]));
+            }
+        }
+
+        let mut by_first : HashMap<u8, Vec<usize>> = HashMap::new();
+        let mut wildcard = Vec::new();
+        for (i, form) in forms.iter().enumerate() {
+            match form.immediate {
+                _ if form.template.is_empty() => (),
+                Some((lo, hi)) if lo == 0 || hi == Some(0) => wildcard.push(i),
+                _ => by_first.entry(form.template[0]).or_default().push(i),
+            }
+        }
+
+        Table { forms, by_first, wildcard }
+    })
+}
+
+/// Decodes the instruction at the start of `bytes`
+pub fn decode(bytes : &[u8]) -> Option<Decoded> {
+    let table = table();
+    let first = *bytes.first()?;
+
+    let candidates = table.by_first.get(&first).into_iter().flatten().chain(table.wildcard.iter());
+    for form in candidates.map(|i| &table.forms[*i]) {
+        let len = form.template.len();
+        if len == 0 || bytes.len() < len {
+            continue
+        }
+
+        let Some((lo, hi)) = form.immediate else {
+            if bytes[..len] == form.template[..] {
+                return Some(Decoded { instruction: form.instruction, op: form.op.clone(), operands: form.operands.clone() })
+            }
+            continue
+        };
+
+        if !(0..len).all(|i| i == lo || Some(i) == hi || bytes[i] == form.template[i]) {
+            continue
+        }
+
+        let value = bytes[lo] as i64 | hi.map_or(0, |hi| (bytes[hi] as i64) << 8);
+        let operands : Vec<Token> = form.operands.iter()
+            .map(|operand| if matches!(operand, Token::Number(_)) { Token::Number(value) } else { operand.clone() })
+            .collect();
+        match build(&form.op, &operands) {
+            Some(instruction) if compile(instruction) == bytes[..len] =>
+                return Some(Decoded { instruction, op: form.op.clone(), operands }),
+            _ => continue,
+        }
+    }
+
+    None
+}
+
+/// Decodes every instruction in `bytes`, loaded at `base`. Bytes that don't decode become `db`s.
+pub fn decode_all(bytes : &[u8], base : u16) -> Result<Vec<(u16, Decoded)>> {
+    utils::check_fits(bytes, base)?;
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = decode(&bytes[i..]).unwrap_or_else(|| Decoded::db(bytes[i]));
+        let len = (decoded.len() as usize).max(1);
+        res.push((base.wrapping_add(i as u16), decoded));
+        i += len;
+    }
+    Ok(res)
+}
+
+/// Decodes `bytes`, loaded at `base`, back into instructions. Bytes that don't decode become `db`s.
+pub fn disassemble(bytes : &[u8], base : u16) -> Result<Vec<(u16, Instruction)>> {
+    Ok(decode_all(bytes, base)?.into_iter().map(|(address, decoded)| (address, decoded.instruction)).collect())
+}
+
+/// Writes an instruction the way `tokenize` reads it
+pub fn render(instruction : &Instruction) -> String {
+    let bytes = compile(*instruction);
+    match decode(&bytes) {
+        Some(decoded) if decoded.instruction == *instruction => decoded.to_string(),
+        _ => format!("db {}", bytes.iter().map(|b| format!("{b:#04X}")).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+fn is_word(reg : &Token) -> bool {
+    matches!(reg, Token::Register(reg) if utils::register_index(*reg).is_ok_and(|(_, byte)| !byte))
+}
+
+/// Where control goes after `decoded`, when `prev` loads its target register with a constant
+fn target(prev : Option<&Decoded>, decoded : &Decoded, next : u16) -> Option<u16> {
+    use Token::*;
+    match (&decoded.op, &decoded.operands[..]) {
+        (Call, [Number(value)]) => Some(*value as u16),
+        (AJmp | Call | Jmp | Jeq | Jneq | Jlt | Jgt | Jleq | Jgeq | Jo | Jno, [Register(reg)]) => {
+            let prev = prev?;
+            match (&prev.op, &prev.operands[..]) {
+                (Mov, [Number(value), dest @ Register(dest_reg)]) if dest_reg == reg && is_word(dest) =>
+                    Some(if matches!(decoded.op, AJmp | Call) { *value as u16 } else { next.wrapping_add(*value as u16) }),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Disassembles `bytes`, loaded at `base`, into source that assembles back into the same bytes. Code is
+/// told apart from data by following control flow from `entries`, and jump and call targets that can be
+/// worked out get `L_XXXX` labels.
+pub fn disassemble_source(bytes : &[u8], base : u16, entries : &[u16]) -> Result<String> {
+    use std::collections::{BTreeMap, BTreeSet};
+    use Token::*;
+
+    utils::check_fits(bytes, base)?;
+    let addr = |offset : usize| base.wrapping_add(offset as u16);
+    let offset = |address : u16| Some(address.wrapping_sub(base) as usize).filter(|offset| *offset < bytes.len());
+
+    // Follow control flow, one path at a time
+    let mut owner = vec![None; bytes.len()];
+    let mut code : BTreeMap<usize, Decoded> = BTreeMap::new();
+    let mut targets = BTreeSet::new();
+    let mut paths : Vec<usize> = entries.iter().filter_map(|entry| offset(*entry)).collect();
+    while let Some(mut at) = paths.pop() {
+        let mut prev : Option<Decoded> = None;
+        while at < bytes.len() && owner[at].is_none() {
+            let Some(decoded) = decode(&bytes[at..]) else { break };
+            let next = at + decoded.len() as usize;
+            if next == at || (at..next).any(|i| owner[i].is_some()) {
+                break
+            }
+            owner[at..next].fill(Some(at));
+
+            let target = target(prev.as_ref(), &decoded, addr(next));
+            if let Some(target) = target {
+                targets.insert(target);
+                paths.extend(offset(target));
+            }
+
+            let ends = matches!(decoded.op, Ret | Jmp | AJmp);
+            code.insert(at, decoded.clone());
+            if ends {
+                break
+            }
+            prev = Some(decoded);
+            at = next;
+        }
+    }
+
+    // Label targets, and constants that point at the start of something
+    let starts = |address : &u16| offset(*address).is_some_and(|offset| owner[offset].is_none() || owner[offset] == Some(offset));
+    let mut labels : BTreeSet<u16> = targets.into_iter().filter(starts).collect();
+    for decoded in code.values() {
+        if let (Mov, [Number(value), dest]) = (&decoded.op, &decoded.operands[..]) {
+            if is_word(dest) && starts(&(*value as u16)) {
+                labels.insert(*value as u16);
+            }
+        }
+    }
+    let label = |address : u16| format!("L_{address:04X}");
+
+    let mut res = String::new();
+    if base != 0 {
+        res.push_str(&format!(".org {base:#06X}\n"));
+    }
+
+    let mut at = 0;
+    while at < bytes.len() {
+        if labels.contains(&addr(at)) {
+            res.push_str(&format!("{}:\n", label(addr(at))));
+        }
+
+        let Some(decoded) = code.get(&at) else {
+            // Data runs up to the next label or instruction
+            let end = (at + 1..bytes.len())
+                .find(|i| code.contains_key(i) || labels.contains(&addr(*i)))
+                .unwrap_or(bytes.len());
+            let data = &bytes[at..end];
+            for words in data.chunks(16).map(|chunk| chunk.chunks_exact(2)) {
+                let values : Vec<String> = words.map(|word| format!("{:#06X}", word[0] as u16 | (word[1] as u16) << 8)).collect();
+                if !values.is_empty() {
+                    res.push_str(&format!("    dw {}\n", values.join(", ")));
+                }
+            }
+            if data.len() % 2 == 1 {
+                res.push_str(&format!("    db {:#04X}\n", data[data.len() - 1]));
+            }
+            at = end;
+            continue
+        };
+
+        let next = at + decoded.len() as usize;
+        let following = code.get(&next).filter(|_| !labels.contains(&addr(next)));
+        let line = match (&decoded.op, &decoded.operands[..], following) {
+            // A constant loaded right before jumping through it becomes a label jump
+            (Mov, [Number(_), dest @ Register(reg)], Some(jump)) if is_word(dest) && jump.operands == [Register(*reg)] => {
+                let next_jump = addr(next + jump.len() as usize);
+                match target(Some(decoded), jump, next_jump) {
+                    Some(target) if labels.contains(&target) && !matches!(jump.op, Call) => {
+                        let line = format!("{} {}, {}", jump.op.mnemonic().unwrap_or("?"), label(target), utils::register_name(*reg));
+                        at = next + jump.len() as usize;
+                        res.push_str(&format!("    {line}\n"));
+                        continue
+                    },
+                    _ => decoded.to_string(),
+                }
+            },
+            (Mov, [Number(value), dest], _) if is_word(dest) && labels.contains(&(*value as u16)) =>
+                format!("mov {}, {}", label(*value as u16), render_operand(dest)),
+            (Call, [Number(value)], _) if labels.contains(&(*value as u16)) =>
+                format!("call {}", label(*value as u16)),
+            _ => decoded.to_string(),
+        };
+        res.push_str(&format!("    {line}\n"));
+        at = next;
+    }
+
+    Ok(res)
+}
@@ -8,8 +8,19 @@ pub enum Expr {
     Instruction(Instruction),
     DB(Vec<u8>),
     IdentifierDef(String),
+    Constant(String, u16),
+    Section(String),
+    Org(u16),
 
     MovC2R(String, Register, bool),
+    CallC(String),
+    Jump(String, Register, bool, Instruction),
+
+    Expansion(Vec<(String, Expr)>),
+}
+
+fn lookup(identifiers : &HashMap<String, u16>, ident : &str) -> Result<u16> {
+    identifiers.get(ident).copied().ok_or(Error::NoSuchIdentifier(ident.to_string()))
 }
 
 impl Expr {
@@ -17,20 +28,36 @@ impl Expr {
         match self {
             Expr::Instruction(instruction) => Ok(vec![*instruction]),
             Expr::DB(values) => Ok(values.iter().map(|value| Instruction::db(*value)).collect()),
-            Expr::IdentifierDef(_) => Ok(vec![]),
+            Expr::IdentifierDef(_) | Expr::Constant(_, _) | Expr::Section(_) | Expr::Org(_) => Ok(vec![]),
             Self::MovC2R(ident, dest, relative) => Ok(vec![Instruction::movc2r(
                 Value::word({
-                    let ident_offset = *identifiers.get(ident).ok_or(Error::NoSuchIdentifier(ident.clone()))?;
+                    let ident_offset = lookup(identifiers, ident)?;
                     if *relative {
                         if ident_offset < offset {
                             ident_offset.wrapping_sub(offset)
-                        } else { 
+                        } else {
                             offset.wrapping_sub(ident_offset)
                         }
                     } else { ident_offset }
                 }),
                 *dest
             )?]),
+            Self::CallC(ident) => Ok(vec![Instruction::callc(Value::word(lookup(identifiers, ident)?))?]),
+            Self::Jump(ident, scratch, relative, jump) => {
+                // Relative jumps are taken from the address right after the jump
+                let target = lookup(identifiers, ident)?;
+                let value = if *relative { target.wrapping_sub(offset.wrapping_add(self.len())) } else { target };
+                Ok(vec![Instruction::movc2r(Value::word(value), *scratch)?, *jump])
+            },
+            Self::Expansion(exprs) => {
+                let mut res = Vec::new();
+                let mut offset = offset;
+                for (_, expr) in exprs.iter() {
+                    res.append(&mut expr.to_instructions(identifiers, offset)?);
+                    offset = offset.wrapping_add(expr.len());
+                }
+                Ok(res)
+            },
         }
     }
 
@@ -39,9 +66,22 @@ impl Expr {
         match self {
             Expr::Instruction(instruction) => instruction.len(),
             Expr::DB(values) => values.len().try_into().unwrap(),
-            Expr::IdentifierDef(_) => 0,
+            Expr::IdentifierDef(_) | Expr::Constant(_, _) | Expr::Section(_) | Expr::Org(_) => 0,
             Self::MovC2R(_, dest, _) =>
                 Instruction::movc2r(Value::word(0), *dest).unwrap().len(), // TODO: Don't unwrap
+            Self::CallC(_) => Instruction::callc(Value::word(0)).unwrap().len(),
+            Self::Jump(_, scratch, _, jump) =>
+                Instruction::movc2r(Value::word(0), *scratch).unwrap().len() + jump.len(),
+            Self::Expansion(exprs) => exprs.iter().map(|(_, expr)| expr.len()).sum(),
+        }
+    }
+
+    /// Identifiers this expression needs to be resolved
+    pub fn references(&self) -> Vec<&str> {
+        match self {
+            Self::MovC2R(ident, _, _) | Self::CallC(ident) | Self::Jump(ident, _, _, _) => vec![ident.as_str()],
+            Self::Expansion(exprs) => exprs.iter().flat_map(|(_, expr)| expr.references()).collect(),
+            _ => vec![],
         }
     }
 }
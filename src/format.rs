@@ -0,0 +1,140 @@
+use crate::{Program, utils::{Error, Result}};
+
+pub mod elf;
+pub mod embed;
+pub mod hdl;
+pub mod ihex;
+pub mod split;
+pub mod srec;
+
+/// Image file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Raw binary
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-records
+    Srec,
+    /// Verilog $readmemh text
+    Readmemh,
+    /// Verilog $readmemb text
+    Readmemb,
+    /// Intel/Altera memory initialisation file
+    Mif,
+    /// Xilinx coefficients file
+    Coe,
+    /// Logisim v2.0 raw image
+    Logisim,
+    /// C header with a byte array
+    C,
+    /// Rust module with a byte array
+    Rust,
+    /// xxd style hex dump
+    Hexdump,
+    /// ELF32 executable, with symbols and debug info
+    Elf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Data bytes per record, for record based formats
+    pub record_len : usize,
+    /// Bits per ROM word, 8 or 16, for memory initialisation formats
+    pub word_width : u8,
+    /// Whether 16 bit ROM words hold their first byte in the high half
+    pub big_endian : bool,
+    /// ROM depth in words to pad the image to
+    pub depth : Option<usize>,
+    /// Name of the array, and prefix of the constants, for source formats
+    pub name : String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { record_len: 16, word_width: 8, big_endian: false, depth: None, name: "program".to_string() }
+    }
+}
+
+impl Format {
+    /// Guesses the format of a file from its extension
+    pub fn from_path(fpath : &str) -> Self {
+        match std::path::Path::new(fpath).extension().and_then(|ext| ext.to_str()) {
+            Some("hex" | "ihex" | "ihx") => Self::Ihex,
+            Some("srec" | "s19" | "mot") => Self::Srec,
+            Some("mif") => Self::Mif,
+            Some("coe") => Self::Coe,
+            Some("h" | "c") => Self::C,
+            Some("rs") => Self::Rust,
+            Some("elf") => Self::Elf,
+            _ => Self::Bin,
+        }
+    }
+
+    /// Encodes `bytes`, to be loaded at `base`
+    pub fn write(&self, bytes : &[u8], base : u16, options : &Options) -> Result<Vec<u8>> {
+        match self {
+            Self::Bin => Ok(bytes.to_vec()),
+            Self::Ihex => ihex::write(bytes, base, options.record_len).map(String::into_bytes),
+            Self::Srec => srec::write(bytes, base, options.record_len).map(String::into_bytes),
+            Self::Readmemh => hdl::readmemh(bytes, options).map(String::into_bytes),
+            Self::Readmemb => hdl::readmemb(bytes, options).map(String::into_bytes),
+            Self::Mif => hdl::mif(bytes, options).map(String::into_bytes),
+            Self::Coe => hdl::coe(bytes, options).map(String::into_bytes),
+            Self::Logisim => hdl::logisim(bytes, options).map(String::into_bytes),
+            Self::C => embed::c(bytes, base, &[], &options.name).map(String::into_bytes),
+            Self::Rust => embed::rust(bytes, base, &[], &options.name).map(String::into_bytes),
+            Self::Hexdump => Ok(embed::hexdump(bytes, base).into_bytes()),
+            Self::Elf => Ok(elf::write(bytes, base, &[], None)),
+        }
+    }
+
+    /// Encodes an assembled program, along with its symbols where the format has room for them
+    pub fn write_program(&self, program : &Program, options : &Options) -> Result<Vec<u8>> {
+        let mut symbols : Vec<(&str, u16)> = program.identifiers.iter().map(|(name, value)| (name.as_str(), *value)).collect();
+        symbols.sort_by_key(|(name, value)| (*value, *name));
+
+        let bytes = program.bytes();
+        match self {
+            Self::C => embed::c(&bytes, program.base, &symbols, &options.name).map(String::into_bytes),
+            Self::Rust => embed::rust(&bytes, program.base, &symbols, &options.name).map(String::into_bytes),
+            Self::Elf => Ok(elf::write_program(program)),
+            _ => self.write(&bytes, program.base, options),
+        }
+    }
+
+    /// Decodes an image, returning the address it's loaded at along with its bytes
+    pub fn read(&self, data : &[u8], base : u16) -> Result<(u16, Vec<u8>)> {
+        let text = || std::str::from_utf8(data).map_err(|err| Error::External(err.to_string()));
+        match self {
+            Self::Bin => Ok((base, data.to_vec())),
+            Self::Ihex => flatten(ihex::read(text()?)?),
+            Self::Srec => flatten(srec::read(text()?)?),
+            Self::Elf => elf::read(data).map(|elf| (elf.base, elf.bytes)),
+            _ => Err(Error::External(format!("{self:?} files can't be read"))),
+        }
+    }
+}
+
+/// Lays out `(address, byte)` pairs as a contiguous image, filling any gaps with zeros
+pub(crate) fn flatten(data : Vec<(u16, u8)>) -> Result<(u16, Vec<u8>)> {
+    let Some(base) = data.iter().map(|(address, _)| *address).min() else { return Ok((0, Vec::new())) };
+    let end = data.iter().map(|(address, _)| *address as usize).max().unwrap_or(0) + 1;
+
+    let mut bytes = vec![0; end - base as usize];
+    for (address, byte) in data.into_iter() {
+        bytes[(address - base) as usize] = byte;
+    }
+    Ok((base, bytes))
+}
+
+pub(crate) fn hex_byte(text : &str, at : usize) -> Option<u8> {
+    text.get(at..at + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())
+}
+
+pub(crate) fn hex_bytes(text : &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None
+    }
+    (0..text.len()).step_by(2).map(|at| hex_byte(text, at)).collect()
+}
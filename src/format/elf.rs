@@ -0,0 +1,269 @@
+//! ELF32 images, so binutils can look at sasm output.
+//!
+//! Images are little endian executables with a single loadable segment. Every sasm section gets an
+//! ELF section of its own, anything before the first one going in `.text`. Symbols go in `.symtab`,
+//! with constants as absolute symbols, and the debug info text in `.sasm.debug`.
+
+use crate::{Program, Symbol, SymbolKind, debug::DebugInfo, utils::{Error, Result}};
+
+/// `e_machine` of sasm images, outside the range of assigned machines
+pub const MACHINE : u16 = 0x534D;
+
+const EHDR_SIZE : usize = 52;
+const PHDR_SIZE : usize = 32;
+const SHDR_SIZE : usize = 40;
+const SYM_SIZE : usize = 16;
+
+const SHT_PROGBITS : u32 = 1;
+const SHT_SYMTAB : u32 = 2;
+const SHT_STRTAB : u32 = 3;
+
+const SHF_WRITE : u32 = 1;
+const SHF_ALLOC : u32 = 2;
+const SHF_EXECINSTR : u32 = 4;
+
+const STB_LOCAL : u8 = 0;
+const STB_GLOBAL : u8 = 1;
+const STT_NOTYPE : u8 = 0;
+const STT_SECTION : u8 = 3;
+const SHN_ABS : u16 = 0xFFF1;
+
+/// A loaded ELF image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub entry : u16,
+    pub base : u16,
+    pub bytes : Vec<u8>,
+    pub symbols : Vec<Symbol>,
+    pub debug : Option<DebugInfo>,
+}
+
+#[derive(Default)]
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn add(&mut self, name : &str) -> u32 {
+        if self.0.is_empty() {
+            self.0.push(0);
+        }
+        let at = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        at
+    }
+}
+
+struct Section {
+    name : u32,
+    kind : u32,
+    flags : u32,
+    address : u32,
+    data : Vec<u8>,
+    link : u32,
+    info : u32,
+    entsize : u32,
+}
+
+fn push16(res : &mut Vec<u8>, value : u16) {
+    res.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push32(res : &mut Vec<u8>, value : u32) {
+    res.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Splits the image at every section symbol, as `(name, start, end)`
+fn regions(base : u16, len : usize, symbols : &[Symbol]) -> Vec<(String, u16, usize)> {
+    let end = base as usize + len;
+    let mut starts : Vec<(&str, u16)> = symbols.iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Section && (base as usize..end).contains(&(symbol.address as usize)))
+        .map(|symbol| (symbol.name.as_str(), symbol.address))
+        .collect();
+    starts.sort_by_key(|(_, address)| *address);
+    if starts.first().map(|(_, address)| *address != base).unwrap_or(true) {
+        starts.insert(0, (".text", base));
+    }
+
+    let ends = starts.iter().skip(1).map(|(_, address)| *address as usize).chain([end]);
+    starts.iter().zip(ends).map(|((name, start), end)| (name.to_string(), *start, end)).collect()
+}
+
+/// Lays out `bytes` loaded at `base` as an ELF image
+pub fn write(bytes : &[u8], base : u16, symbols : &[Symbol], debug : Option<&DebugInfo>) -> Vec<u8> {
+    let mut names = Strings::default();
+    let mut sections = Vec::new();
+
+    let regions = regions(base, bytes.len(), symbols);
+    for (name, start, end) in regions.iter() {
+        let data = bytes[(start - base) as usize..end - base as usize].to_vec();
+        let flags = SHF_ALLOC | SHF_WRITE | SHF_EXECINSTR;
+        sections.push(Section { name: names.add(name), kind: SHT_PROGBITS, flags, address: *start as u32, data, link: 0, info: 0, entsize: 0 });
+    }
+    if let Some(debug) = debug {
+        let data = debug.to_string().into_bytes();
+        sections.push(Section { name: names.add(".sasm.debug"), kind: SHT_PROGBITS, flags: 0, address: 0, data, link: 0, info: 0, entsize: 0 });
+    }
+
+    // Section symbols are local, so they have to come before the rest
+    let mut symbols : Vec<&Symbol> = symbols.iter().collect();
+    symbols.sort_by_key(|symbol| symbol.kind != SymbolKind::Section);
+    let locals = 1 + symbols.iter().filter(|symbol| symbol.kind == SymbolKind::Section).count();
+
+    let mut strings = Strings::default();
+    let mut symtab = vec![0; SYM_SIZE];
+    for symbol in symbols.iter() {
+        let index = regions.iter().position(|(_, start, end)| *start <= symbol.address && (symbol.address as usize) < *end)
+            .or_else(|| regions.iter().position(|(_, _, end)| *end == symbol.address as usize));
+        let (info, shndx) = match (symbol.kind, index) {
+            (SymbolKind::Constant, _) | (_, None) => (STB_GLOBAL << 4 | STT_NOTYPE, SHN_ABS),
+            (SymbolKind::Section, Some(index)) => (STB_LOCAL << 4 | STT_SECTION, index as u16 + 1),
+            (SymbolKind::Label, Some(index)) => (STB_GLOBAL << 4 | STT_NOTYPE, index as u16 + 1),
+        };
+        push32(&mut symtab, strings.add(&symbol.name));
+        push32(&mut symtab, symbol.address as u32);
+        push32(&mut symtab, symbol.size.unwrap_or(0) as u32);
+        symtab.extend_from_slice(&[info, 0]);
+        push16(&mut symtab, shndx);
+    }
+
+    let strtab = sections.len() as u32 + 2;
+    let (symtab_name, strtab_name) = (names.add(".symtab"), names.add(".strtab"));
+    sections.push(Section {
+        name: symtab_name, kind: SHT_SYMTAB, flags: 0, address: 0, data: symtab, link: strtab, info: locals as u32, entsize: SYM_SIZE as u32,
+    });
+    sections.push(Section { name: strtab_name, kind: SHT_STRTAB, flags: 0, address: 0, data: strings.0, link: 0, info: 0, entsize: 0 });
+    let shstrtab_name = names.add(".shstrtab");
+    sections.push(Section { name: shstrtab_name, kind: SHT_STRTAB, flags: 0, address: 0, data: names.0, link: 0, info: 0, entsize: 0 });
+
+    let mut offsets = Vec::new();
+    let mut offset = EHDR_SIZE + PHDR_SIZE;
+    for section in sections.iter() {
+        offset = offset.next_multiple_of(if section.kind == SHT_SYMTAB { 4 } else { 1 });
+        offsets.push(offset);
+        offset += section.data.len();
+    }
+    let shoff = offset.next_multiple_of(4);
+
+    let mut res = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+    res.resize(16, 0);
+    push16(&mut res, 2);
+    push16(&mut res, MACHINE);
+    push32(&mut res, 1);
+    push32(&mut res, base as u32);
+    push32(&mut res, EHDR_SIZE as u32);
+    push32(&mut res, shoff as u32);
+    push32(&mut res, 0);
+    for value in [EHDR_SIZE, PHDR_SIZE, 1, SHDR_SIZE, sections.len() + 1, sections.len()] {
+        push16(&mut res, value as u16);
+    }
+
+    // One loadable segment, the image sections being laid out back to back
+    for value in [1, offsets[0], base as usize, base as usize, bytes.len(), bytes.len(), 7, 1] {
+        push32(&mut res, value as u32);
+    }
+
+    for (section, offset) in sections.iter().zip(offsets.iter()) {
+        res.resize(*offset, 0);
+        res.extend_from_slice(&section.data);
+    }
+    res.resize(shoff + SHDR_SIZE, 0);
+    for (section, offset) in sections.iter().zip(offsets.iter()) {
+        let align = if section.kind == SHT_SYMTAB { 4 } else { 1 };
+        for value in [section.name, section.kind, section.flags, section.address, *offset as u32, section.data.len() as u32,
+                      section.link, section.info, align, section.entsize] {
+            push32(&mut res, value);
+        }
+    }
+    res
+}
+
+/// Lays out an assembled program as an ELF image, with its symbols and debug info
+pub fn write_program(program : &Program) -> Vec<u8> {
+    write(&program.bytes(), program.base, &program.symbols, Some(&DebugInfo::new(program)))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, at : usize, len : usize) -> Result<&[u8]> {
+        self.0.get(at..at + len).ok_or_else(|| Error::External("truncated ELF file".to_string()))
+    }
+
+    fn u16(&self, at : usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(at, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, at : usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(at, 4)?.try_into().unwrap()))
+    }
+
+    fn string(&self, at : usize) -> Result<String> {
+        let rest = self.bytes(at, 0).map(|_| &self.0[at..])?;
+        let len = rest.iter().position(|byte| *byte == 0).unwrap_or(rest.len());
+        Ok(String::from_utf8_lossy(&rest[..len]).to_string())
+    }
+}
+
+/// Loads an ELF image written by [`write`]
+pub fn read(data : &[u8]) -> Result<Elf> {
+    let elf = Reader(data);
+    if elf.bytes(0, 7)? != [0x7F, b'E', b'L', b'F', 1, 1, 1] {
+        return Err(Error::External("not a little endian ELF32 file".to_string()))
+    }
+    if elf.u16(18)? != MACHINE {
+        return Err(Error::External(format!("ELF machine {:#06X} isn't sasm's", elf.u16(18)?)))
+    }
+
+    let entry = elf.u32(24)? as u16;
+    let (shoff, shnum, shstrndx) = (elf.u32(32)? as usize, elf.u16(48)? as usize, elf.u16(50)? as usize);
+    // (name, kind, flags, address, offset, size, link)
+    let headers = (0..shnum).map(|i| {
+        let at = shoff + i * SHDR_SIZE;
+        Ok((elf.u32(at)? as usize, elf.u32(at + 4)?, elf.u32(at + 8)?, elf.u32(at + 12)?,
+            elf.u32(at + 16)? as usize, elf.u32(at + 20)? as usize, elf.u32(at + 24)? as usize))
+    }).collect::<Result<Vec<_>>>()?;
+    let shstrtab = headers.get(shstrndx).map(|header| header.4).unwrap_or(0);
+
+    let mut image = Vec::new();
+    let mut symbols = Vec::new();
+    let mut debug = None;
+    for (name, kind, flags, address, offset, size, link) in headers.iter().copied() {
+        let contents = elf.bytes(offset, size)?;
+        match kind {
+            SHT_PROGBITS if flags & SHF_ALLOC != 0 => image.extend(contents.iter().enumerate()
+                .map(|(i, byte)| ((address as usize + i) as u16, *byte))),
+            SHT_PROGBITS if elf.string(shstrtab + name)? == ".sasm.debug" =>
+                debug = Some(DebugInfo::parse(&String::from_utf8_lossy(contents))?),
+            SHT_SYMTAB => {
+                let strtab = headers.get(link).map(|header| header.4).unwrap_or(0);
+                for at in (offset..offset + size).step_by(SYM_SIZE).skip(1) {
+                    let (name, address, size) = (elf.u32(at)? as usize, elf.u32(at + 4)? as u16, elf.u32(at + 8)? as u16);
+                    let kind = match (elf.bytes(at + 12, 1)?[0] & 0xF, elf.u16(at + 14)?) {
+                        (STT_SECTION, _) => SymbolKind::Section,
+                        (_, SHN_ABS) => SymbolKind::Constant,
+                        _ => SymbolKind::Label,
+                    };
+                    let mut symbol = Symbol::new(&elf.string(strtab + name)?, address, kind, None);
+                    symbol.size = if size == 0 { None } else { Some(size) };
+                    symbols.push(symbol);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // Labels belong to the last section starting before them
+    let sections : Vec<(String, u16)> = symbols.iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Section)
+        .map(|symbol| (symbol.name.clone(), symbol.address))
+        .collect();
+    for symbol in symbols.iter_mut().filter(|symbol| symbol.kind != SymbolKind::Constant) {
+        symbol.section = sections.iter()
+            .filter(|(_, address)| *address <= symbol.address)
+            .max_by_key(|(_, address)| *address)
+            .map(|(name, _)| name.clone());
+    }
+
+    let (base, bytes) = super::flatten(image)?;
+    Ok(Elf { entry, base, bytes, symbols, debug })
+}
@@ -0,0 +1,64 @@
+use std::{collections::HashMap, fmt::Write};
+
+use crate::utils::{Error, Result};
+
+/// Turns `name` into a C and Rust identifier
+pub fn identifier(name : &str) -> String {
+    let res : String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    match res.chars().next() {
+        Some(c) if !c.is_ascii_digit() => res,
+        _ => format!("_{res}"),
+    }
+}
+
+/// `{NAME}_BASE` and a constant for every symbol, as long as no two of them end up with the same name
+fn constants(name : &str, base : u16, symbols : &[(&str, u16)]) -> Result<Vec<(String, u16)>> {
+    let prefix = identifier(name).to_uppercase();
+    let mut res = vec![(format!("{prefix}_BASE"), base)];
+    let mut seen = HashMap::from([(res[0].0.clone(), "the base address")]);
+    for (symbol, value) in symbols.iter() {
+        let constant = format!("{prefix}_{}", identifier(symbol).to_uppercase());
+        if let Some(other) = seen.insert(constant.clone(), *symbol) {
+            return Err(Error::External(format!("{symbol} and {other} would both be {constant}")))
+        }
+        res.push((constant, *value));
+    }
+    Ok(res)
+}
+
+fn array(bytes : &[u8]) -> String {
+    bytes.chunks(12)
+        .map(|line| format!("    {},\n", line.iter().map(|byte| format!("0x{byte:02X}")).collect::<Vec<_>>().join(", ")))
+        .collect()
+}
+
+/// C header with the image as a byte array and symbols as `#define`s
+pub fn c(bytes : &[u8], base : u16, symbols : &[(&str, u16)], name : &str) -> Result<String> {
+    let mut res = "/* Generated by sasm */\n#include <stdint.h>\n\n".to_string();
+    for (constant, value) in constants(name, base, symbols)? {
+        writeln!(res, "#define {constant} 0x{value:04X}u").unwrap();
+    }
+    write!(res, "\nstatic const uint8_t {}[{}] = {{\n{}}};\n", identifier(name), bytes.len(), array(bytes)).unwrap();
+    Ok(res)
+}
+
+/// Rust module with the image as a byte array and symbols as `u16` constants
+pub fn rust(bytes : &[u8], base : u16, symbols : &[(&str, u16)], name : &str) -> Result<String> {
+    let mut res = "// Generated by sasm\n\n".to_string();
+    for (constant, value) in constants(name, base, symbols)? {
+        writeln!(res, "pub const {constant}: u16 = 0x{value:04X};").unwrap();
+    }
+    write!(res, "\npub const {}: [u8; {}] = [\n{}];\n", identifier(name).to_uppercase(), bytes.len(), array(bytes)).unwrap();
+    Ok(res)
+}
+
+/// `xxd` style dump, 16 bytes per line
+pub fn hexdump(bytes : &[u8], base : u16) -> String {
+    let mut res = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let groups : Vec<String> = line.chunks(2).map(|group| group.iter().map(|byte| format!("{byte:02x}")).collect()).collect();
+        let ascii : String = line.iter().map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' }).collect();
+        writeln!(res, "{:08x}: {:<39}  {ascii}", base as usize + i * 16, groups.join(" ")).unwrap();
+    }
+    res
+}
@@ -0,0 +1,75 @@
+use std::fmt::Write;
+
+use crate::utils::{Error, Result};
+use super::Options;
+
+/// Groups `bytes` into ROM words, padded to the configured depth
+pub fn words(bytes : &[u8], options : &Options) -> Result<Vec<u16>> {
+    let mut words : Vec<u16> = match options.word_width {
+        8 => bytes.iter().map(|byte| *byte as u16).collect(),
+        16 => bytes.chunks(2).map(|pair| {
+            let (first, second) = (pair[0] as u16, pair.get(1).copied().unwrap_or(0) as u16);
+            if options.big_endian { first << 8 | second } else { second << 8 | first }
+        }).collect(),
+        width => return Err(Error::External(format!("ROM words are 8 or 16 bits wide, not {width}"))),
+    };
+
+    if let Some(depth) = options.depth {
+        if words.len() > depth {
+            return Err(Error::External(format!("{} words don't fit in a ROM {depth} words deep", words.len())))
+        }
+        words.resize(depth, 0);
+    }
+    Ok(words)
+}
+
+fn hex_digits(options : &Options) -> usize {
+    options.word_width as usize / 4
+}
+
+/// Verilog `$readmemh` text, one word per line
+pub fn readmemh(bytes : &[u8], options : &Options) -> Result<String> {
+    let digits = hex_digits(options);
+    Ok(words(bytes, options)?.iter().map(|word| format!("{word:0digits$X}\n")).collect())
+}
+
+/// Verilog `$readmemb` text, one word per line
+pub fn readmemb(bytes : &[u8], options : &Options) -> Result<String> {
+    let digits = options.word_width as usize;
+    Ok(words(bytes, options)?.iter().map(|word| format!("{word:0digits$b}\n")).collect())
+}
+
+/// Intel/Altera memory initialisation file
+pub fn mif(bytes : &[u8], options : &Options) -> Result<String> {
+    let words = words(bytes, options)?;
+    let digits = hex_digits(options);
+
+    let mut res = String::new();
+    writeln!(res, "DEPTH = {};", words.len()).unwrap();
+    writeln!(res, "WIDTH = {};", options.word_width).unwrap();
+    writeln!(res, "ADDRESS_RADIX = HEX;").unwrap();
+    writeln!(res, "DATA_RADIX = HEX;").unwrap();
+    writeln!(res, "CONTENT BEGIN").unwrap();
+    for (address, word) in words.iter().enumerate() {
+        writeln!(res, "    {address:04X} : {word:0digits$X};").unwrap();
+    }
+    writeln!(res, "END;").unwrap();
+    Ok(res)
+}
+
+/// Xilinx coefficients file
+pub fn coe(bytes : &[u8], options : &Options) -> Result<String> {
+    let digits = hex_digits(options);
+    let words : Vec<String> = words(bytes, options)?.iter().map(|word| format!("{word:0digits$X}")).collect();
+    Ok(format!("memory_initialization_radix=16;\nmemory_initialization_vector=\n{};\n", words.join(",\n")))
+}
+
+/// Logisim `v2.0 raw` image, eight words per line
+pub fn logisim(bytes : &[u8], options : &Options) -> Result<String> {
+    let mut res = "v2.0 raw\n".to_string();
+    for line in words(bytes, options)?.chunks(8) {
+        let line : Vec<String> = line.iter().map(|word| format!("{word:x}")).collect();
+        writeln!(res, "{}", line.join(" ")).unwrap();
+    }
+    Ok(res)
+}
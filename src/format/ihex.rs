@@ -0,0 +1,62 @@
+use std::fmt::Write;
+
+use crate::utils::{Error, Result, check_fits};
+use super::hex_bytes;
+
+fn record(res : &mut String, address : u16, kind : u8, data : &[u8]) {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    bytes.push(checksum);
+
+    res.push(':');
+    for byte in bytes.iter() {
+        write!(res, "{byte:02X}").unwrap();
+    }
+    res.push('\n');
+}
+
+/// Encodes `bytes` loaded at `base` as Intel HEX, `record_len` data bytes per record
+pub fn write(bytes : &[u8], base : u16, record_len : usize) -> Result<String> {
+    if !(1..=255).contains(&record_len) {
+        return Err(Error::External(format!("Intel HEX records hold 1 to 255 bytes, not {record_len}")))
+    }
+
+    check_fits(bytes, base)?;
+    let mut res = String::new();
+    for (i, chunk) in bytes.chunks(record_len).enumerate() {
+        record(&mut res, base + (i * record_len) as u16, 0x00, chunk);
+    }
+    record(&mut res, 0, 0x01, &[]);
+    Ok(res)
+}
+
+/// Decodes Intel HEX into `(address, byte)` pairs
+pub fn read(text : &str) -> Result<Vec<(u16, u8)>> {
+    let malformed = |line : &str| Error::External(format!("malformed Intel HEX record {line}"));
+
+    let mut res = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let bytes = line.strip_prefix(':').and_then(hex_bytes).ok_or_else(|| malformed(line))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(malformed(line))
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(Error::External(format!("bad checksum in Intel HEX record {line}")))
+        }
+
+        let address = (bytes[1] as u16) << 8 | bytes[2] as u16;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 if address as usize + data.len() > 0x10000 =>
+                return Err(Error::External(format!("Intel HEX record {line} is out of the 16 bit address space"))),
+            0x00 => res.extend(data.iter().enumerate().map(|(i, byte)| (address + i as u16, *byte))),
+            0x01 => break,
+            // Segments past the first 64 KiB can't be addressed
+            0x02 | 0x04 if data.iter().any(|byte| *byte != 0) =>
+                return Err(Error::External(format!("Intel HEX record {line} is out of the 16 bit address space"))),
+            _ => (),
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,64 @@
+use std::fmt::Write;
+
+use crate::utils::{Error, Result};
+
+/// How an image is spread over several ROM chips
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    /// Even bytes on one chip, odd bytes on the other, for a 16 bit bus made of 8 bit chips
+    EvenOdd,
+    /// Consecutive banks of the given size, one per chip
+    Banks(usize),
+}
+
+/// The contents of one ROM chip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chip {
+    pub name : String,
+    /// Address of the chip's first byte
+    pub address : u16,
+    pub bytes : Vec<u8>,
+    /// Bytes of the image on the chip, before padding
+    pub used : usize,
+}
+
+/// Splits `bytes`, loaded at `base`, over chips of `chip_size` bytes, padding each with `fill`. Chips
+/// start from `base` rather than from 0, though an odd `base` puts the first byte on the odd chip.
+pub fn split(bytes : &[u8], base : u16, split : Split, chip_size : usize, fill : u8) -> Result<Vec<Chip>> {
+    let parts : Vec<(String, u16, Vec<u8>, usize)> = match split {
+        Split::EvenOdd => {
+            // A byte of padding in front keeps even addresses on the even chip
+            let lead = (base % 2) as usize;
+            let aligned : Vec<u8> = std::iter::repeat(fill).take(lead).chain(bytes.iter().copied()).collect();
+            let even : Vec<u8> = aligned.iter().step_by(2).copied().collect();
+            let odd : Vec<u8> = aligned.iter().skip(1).step_by(2).copied().collect();
+            let (start, used) = (base - lead as u16, (even.len() - lead, odd.len()));
+            vec![("even".to_string(), start, even, used.0), ("odd".to_string(), start + 1, odd, used.1)]
+        },
+        Split::Banks(0) => return Err(Error::External("banks can't be empty".to_string())),
+        Split::Banks(size) if size > chip_size =>
+            return Err(Error::External(format!("{size} byte banks don't fit on {chip_size} byte chips"))),
+        Split::Banks(size) => bytes.chunks(size)
+            .enumerate()
+            .map(|(i, bank)| (format!("bank{i}"), base.wrapping_add((i * size) as u16), bank.to_vec(), bank.len()))
+            .collect(),
+    };
+
+    parts.into_iter().map(|(name, address, mut bytes, used)| {
+        if bytes.len() > chip_size {
+            return Err(Error::External(format!("{name} overflows its {chip_size} byte chip by {} bytes", bytes.len() - chip_size)))
+        }
+        bytes.resize(chip_size, fill);
+        Ok(Chip { name, address, bytes, used })
+    }).collect()
+}
+
+/// Where every chip starts and how full it is, one line per chip
+pub fn report(chips : &[Chip]) -> String {
+    let mut res = String::new();
+    for chip in chips.iter() {
+        let percent = chip.used as f64 * 100. / chip.bytes.len().max(1) as f64;
+        writeln!(res, "{:<8}  from {:#06X}  {:>5} / {:>5} bytes  {percent:>5.1}%", chip.name, chip.address, chip.used, chip.bytes.len()).unwrap();
+    }
+    res
+}
@@ -0,0 +1,75 @@
+use std::fmt::Write;
+
+use crate::utils::{Error, Result, check_fits};
+use super::hex_bytes;
+
+fn record(res : &mut String, kind : char, address : &[u8], data : &[u8]) {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let checksum = !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(checksum);
+
+    write!(res, "S{kind}").unwrap();
+    for byte in bytes.iter() {
+        write!(res, "{byte:02X}").unwrap();
+    }
+    res.push('\n');
+}
+
+/// Encodes `bytes` loaded at `base` as Motorola S-records, `record_len` data bytes per record
+pub fn write(bytes : &[u8], base : u16, record_len : usize) -> Result<String> {
+    if !(1..=252).contains(&record_len) {
+        return Err(Error::External(format!("S-records hold 1 to 252 bytes, not {record_len}")))
+    }
+
+    check_fits(bytes, base)?;
+    let mut res = String::new();
+    record(&mut res, '0', &[0, 0], b"sasm");
+    let mut count = 0u16;
+    for (i, chunk) in bytes.chunks(record_len).enumerate() {
+        let address = base + (i * record_len) as u16;
+        record(&mut res, '1', &address.to_be_bytes(), chunk);
+        count = count.wrapping_add(1);
+    }
+    record(&mut res, '5', &count.to_be_bytes(), &[]);
+    record(&mut res, '9', &base.to_be_bytes(), &[]);
+    Ok(res)
+}
+
+/// Decodes Motorola S-records into `(address, byte)` pairs
+pub fn read(text : &str) -> Result<Vec<(u16, u8)>> {
+    let malformed = |line : &str| Error::External(format!("malformed S-record {line}"));
+
+    let mut res = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (kind, bytes) = line.strip_prefix('S')
+            .and_then(|line| Some((line.chars().next()?, hex_bytes(&line[1..])?)))
+            .ok_or_else(|| malformed(line))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(malformed(line))
+        }
+        if !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(Error::External(format!("bad checksum in S-record {line}")))
+        }
+
+        let address_len = match kind {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            '7' | '8' | '9' => break,
+            _ => continue,
+        };
+        if bytes.len() < address_len + 2 {
+            return Err(malformed(line))
+        }
+
+        let address = bytes[1..=address_len].iter().fold(0u32, |address, byte| address << 8 | *byte as u32);
+        let data = &bytes[address_len + 1..bytes.len() - 1];
+        if address as usize + data.len() > 0x10000 {
+            return Err(Error::External(format!("S-record {line} is out of the 16 bit address space")))
+        }
+        res.extend(data.iter().enumerate().map(|(i, byte)| ((address as usize + i) as u16, *byte)));
+    }
+    Ok(res)
+}
@@ -0,0 +1,230 @@
+//! A GDB remote serial protocol stub, so gdb can drive the emulator.
+//!
+//! The register file gdb sees is `r0` to `r15`, then `sp`, `pc` and `flags`, all 16 bits. The flags
+//! are, from bit 0 up, zero, less, greater, overflow and interrupts enabled.
+
+use std::{io::{Read, Write}, net::TcpListener};
+
+use crate::{debugger::{Debugger, Event}, utils::{Error, Result}, vm::{REGISTER_FILE as REGISTERS, Stop}};
+
+/// Describes the register file to gdb
+pub fn target_description() -> String {
+    let mut regs = String::new();
+    for i in 0..16 {
+        regs += &format!("    <reg name=\"r{i}\" bitsize=\"16\" type=\"uint16\" regnum=\"{i}\"/>\n");
+    }
+    regs += "    <reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\" regnum=\"16\"/>\n";
+    regs += "    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"17\"/>\n";
+    regs += "    <reg name=\"flags\" bitsize=\"16\" type=\"uint16\" regnum=\"18\"/>\n";
+    format!("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  \
+        <architecture>smplcore</architecture>\n  <feature name=\"org.smplworks.smplcore\">\n{regs}  </feature>\n</target>\n")
+}
+
+fn hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text : &str) -> Option<Vec<u8>> {
+    crate::format::hex_bytes(text)
+}
+
+fn number(text : &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+/// The stop reply for an event
+fn stop_reply(event : Result<Event>) -> String {
+    match event {
+        Ok(Event::Watchpoint(address, _, _)) => format!("T05watch:{address:x};"),
+        Ok(Event::HistoryStart) => "T05replaylog:begin;".to_string(),
+        Ok(Event::Stopped(Stop::Exit(code))) => format!("W{code:02x}"),
+        Ok(Event::Stopped(Stop::Returned | Stop::Halted)) => "W00".to_string(),
+        Ok(_) => "S05".to_string(),
+        // SIGSEGV
+        Err(_) => "S0b".to_string(),
+    }
+}
+
+/// Copies `data` into memory from `address`
+fn write_memory(dbg : &mut Debugger, address : u16, data : &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        dbg.vm.memory[address.wrapping_add(i as u16) as usize] = *byte;
+    }
+}
+
+/// Answers a single packet, already unescaped, `None` meaning the session's over
+fn answer(dbg : &mut Debugger, packet : &[u8]) -> Option<String> {
+    let error = "E01".to_string();
+    let text = String::from_utf8_lossy(packet);
+    let mut chars = text.chars();
+    let Some(kind) = chars.next() else { return Some(String::new()) };
+    let rest = chars.as_str();
+
+    Some(match kind {
+        '?' => "S05".to_string(),
+        'g' => hex(&dbg.vm.register_file().iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<_>>()),
+        'G' => match unhex(rest) {
+            Some(bytes) if bytes.len() == REGISTERS * 2 => {
+                for (i, value) in bytes.chunks(2).enumerate() {
+                    dbg.vm.set_register(i, u16::from_le_bytes([value[0], value[1]]));
+                }
+                "OK".to_string()
+            },
+            _ => error,
+        },
+        'p' => match usize::from_str_radix(rest, 16) {
+            Ok(i) if i < REGISTERS => hex(&dbg.vm.register_file()[i].to_le_bytes()),
+            _ => error,
+        },
+        'P' => match rest.split_once('=').and_then(|(i, value)| Some((usize::from_str_radix(i, 16).ok()?, unhex(value)?))) {
+            Some((i, value)) if i < REGISTERS && value.len() == 2 => {
+                dbg.vm.set_register(i, u16::from_le_bytes([value[0], value[1]]));
+                "OK".to_string()
+            },
+            _ => error,
+        },
+        'm' => match rest.split_once(',').and_then(|(address, len)| Some((number(address)?, number(len)?))) {
+            Some((address, len)) => hex(&(0..len).map(|i| dbg.vm.memory[address.wrapping_add(i) as usize]).collect::<Vec<_>>()),
+            None => error,
+        },
+        'M' => {
+            let write = rest.split_once(':').and_then(|(range, data)| {
+                let (address, _) = range.split_once(',')?;
+                Some((number(address)?, unhex(data)?))
+            });
+            match write {
+                Some((address, data)) => {
+                    write_memory(dbg, address, &data);
+                    "OK".to_string()
+                },
+                None => error,
+            }
+        },
+        // Like M, with the data in binary
+        'X' => {
+            let colon = packet.iter().position(|byte| *byte == b':');
+            let write = colon.and_then(|colon| {
+                let (address, len) = std::str::from_utf8(&packet[1..colon]).ok()?.split_once(',')?;
+                Some((number(address)?, number(len)?, &packet[colon + 1..]))
+            });
+            match write {
+                Some((address, len, data)) if data.len() == len as usize => {
+                    write_memory(dbg, address, data);
+                    "OK".to_string()
+                },
+                _ => error,
+            }
+        },
+        'Z' | 'z' => {
+            let fields : Vec<&str> = rest.split(',').collect();
+            match (&fields[..], fields.get(1).and_then(|address| number(address))) {
+                (["0" | "1", _, _], Some(address)) => {
+                    if kind == 'Z' { dbg.breakpoints.insert(address); } else { dbg.breakpoints.remove(&address); }
+                    "OK".to_string()
+                },
+                (["2", _, len], Some(address)) => {
+                    for address in (0..number(len).unwrap_or(1)).map(|i| address.wrapping_add(i)) {
+                        if kind == 'Z' {
+                            dbg.watchpoints.insert(address, dbg.vm.memory[address as usize]);
+                        } else {
+                            dbg.watchpoints.remove(&address);
+                        }
+                    }
+                    "OK".to_string()
+                },
+                _ => String::new(),
+            }
+        },
+        's' | 'c' => {
+            if let Some(address) = number(rest) {
+                dbg.vm.pc = address;
+            }
+            stop_reply(if kind == 's' { dbg.step() } else { dbg.cont() })
+        },
+        'b' if rest == "s" => stop_reply(dbg.reverse_step()),
+        'b' if rest == "c" => stop_reply(dbg.reverse_cont()),
+        'H' => "OK".to_string(),
+        'D' | 'k' => return None,
+        'q' if rest.starts_with("Supported") => "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string(),
+        'q' if rest == "Attached" => "1".to_string(),
+        'q' if rest.starts_with("Xfer:features:read:target.xml:") => {
+            let range = rest.rsplit(':').next().and_then(|range| range.split_once(','));
+            match range.and_then(|(offset, len)| Some((usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(len, 16).ok()?))) {
+                Some((offset, len)) => {
+                    let xml = target_description();
+                    let chunk = xml.get(offset.min(xml.len())..(offset + len).min(xml.len())).unwrap_or("");
+                    format!("{}{chunk}", if offset + len >= xml.len() { 'l' } else { 'm' })
+                },
+                None => error,
+            }
+        },
+        _ => String::new(),
+    })
+}
+
+fn send(writer : &mut impl Write, packet : &str) -> std::io::Result<()> {
+    let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(writer, "${packet}#{checksum:02x}")?;
+    writer.flush()
+}
+
+/// Talks to gdb until it detaches, kills the program or goes away
+pub fn session(dbg : &mut Debugger, reader : impl Read, mut writer : impl Write) -> Result<()> {
+    let io = |err : std::io::Error| Error::External(err.to_string());
+    let mut bytes = reader.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte.map_err(io)? {
+            b'$' => (),
+            // ^C while the machine's stopped already
+            0x03 => {
+                send(&mut writer, "S02").map_err(io)?;
+                continue
+            },
+            _ => continue,
+        }
+
+        // The checksum covers the bytes as sent, before `}` escapes are undone
+        let (mut packet, mut expected, mut escaped) = (Vec::new(), 0u8, false);
+        for byte in bytes.by_ref() {
+            match byte.map_err(io)? {
+                b'#' => break,
+                byte => {
+                    expected = expected.wrapping_add(byte);
+                    match (escaped, byte) {
+                        (true, byte) => packet.push(byte ^ 0x20),
+                        (false, b'}') => (),
+                        (false, byte) => packet.push(byte),
+                    }
+                    escaped = !escaped && byte == b'}';
+                },
+            }
+        }
+        let checksum : Vec<u8> = bytes.by_ref().take(2).collect::<std::io::Result<_>>().map_err(io)?;
+        if u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok() != Some(expected) {
+            writer.write_all(b"-").map_err(io)?;
+            continue
+        }
+        writer.write_all(b"+").map_err(io)?;
+
+        match answer(dbg, &packet) {
+            Some(reply) => send(&mut writer, &reply).map_err(io)?,
+            None => {
+                // Detaching gets an answer, killing doesn't
+                if packet.first() == Some(&b'D') {
+                    send(&mut writer, "OK").map_err(io)?;
+                }
+                break
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Waits for gdb to connect on `address`, then runs a session with it
+pub fn serve(dbg : &mut Debugger, address : &str) -> Result<()> {
+    let io = |err : std::io::Error| Error::External(err.to_string());
+    let listener = TcpListener::bind(address).map_err(io)?;
+    eprintln!("waiting for gdb on {}", listener.local_addr().map_err(io)?);
+    let (stream, _) = listener.accept().map_err(io)?;
+    session(dbg, stream.try_clone().map_err(io)?, stream)
+}
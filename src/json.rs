@@ -0,0 +1,289 @@
+//! Just enough JSON for the debug adapter and traces.
+
+use std::fmt::{self, Display};
+
+use crate::utils::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys in the order they were written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<const N : usize>(fields : [(&str, Json); N]) -> Self {
+        Self::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    pub fn get(&self, key : &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(n) if n.fract() == 0. => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text : &str) -> Result<Self> {
+        let mut parser = Parser { text: text.as_bytes(), at: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.at == text.len() { Ok(value) } else { Err(parser.error()) }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value : bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value : &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value : String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(value : Vec<Json>) -> Self {
+        Self::Array(value)
+    }
+}
+
+macro_rules! from_number {
+    ($($t:ty),*) => {$(
+        impl From<$t> for Json {
+            fn from(value : $t) -> Self {
+                Self::Number(value as f64)
+            }
+        }
+    )*};
+}
+from_number!(u8, u16, u32, u64, usize, i64);
+
+struct Parser<'a> {
+    text : &'a [u8],
+    at : usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> Error {
+        Error::External(format!("malformed JSON at byte {}", self.at))
+    }
+
+    fn whitespace(&mut self) {
+        while self.text.get(self.at).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+    }
+
+    fn eat(&mut self, c : u8) -> Result<()> {
+        self.whitespace();
+        match self.text.get(self.at) {
+            Some(got) if *got == c => {
+                self.at += 1;
+                Ok(())
+            },
+            _ => Err(self.error()),
+        }
+    }
+
+    fn literal(&mut self, word : &str, value : Json) -> Result<Json> {
+        if !self.text[self.at..].starts_with(word.as_bytes()) {
+            return Err(self.error())
+        }
+        self.at += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.whitespace();
+        match self.text.get(self.at) {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.at += 1;
+                let mut items = Vec::new();
+                self.whitespace();
+                if self.text.get(self.at) == Some(&b']') {
+                    self.at += 1;
+                    return Ok(Json::Array(items))
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.whitespace();
+                    match self.text.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b']') => {
+                            self.at += 1;
+                            return Ok(Json::Array(items))
+                        },
+                        _ => return Err(self.error()),
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.at += 1;
+                let mut fields = Vec::new();
+                self.whitespace();
+                if self.text.get(self.at) == Some(&b'}') {
+                    self.at += 1;
+                    return Ok(Json::Object(fields))
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.eat(b':')?;
+                    fields.push((key, self.value()?));
+                    self.whitespace();
+                    match self.text.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b'}') => {
+                            self.at += 1;
+                            return Ok(Json::Object(fields))
+                        },
+                        _ => return Err(self.error()),
+                    }
+                }
+            },
+            Some(c) if *c == b'-' || c.is_ascii_digit() => {
+                let start = self.at;
+                while self.text.get(self.at).is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c)) {
+                    self.at += 1;
+                }
+                std::str::from_utf8(&self.text[start..self.at]).ok()
+                    .and_then(|number| number.parse().ok())
+                    .map(Json::Number)
+                    .ok_or_else(|| self.error())
+            },
+            _ => Err(self.error()),
+        }
+    }
+
+    /// The four hex digits of a `\u` escape at `at`
+    fn code_unit(&self, at : usize) -> Option<u32> {
+        u32::from_str_radix(std::str::from_utf8(self.text.get(at..at + 4)?).ok()?, 16).ok()
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.eat(b'"')?;
+        let mut res = Vec::new();
+        loop {
+            let Some(c) = self.text.get(self.at).copied() else { return Err(self.error()) };
+            self.at += 1;
+            match c {
+                b'"' => return String::from_utf8(res).map_err(|_| self.error()),
+                b'\\' => {
+                    let Some(escape) = self.text.get(self.at).copied() else { return Err(self.error()) };
+                    self.at += 1;
+                    let c = match escape {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let mut code = self.code_unit(self.at).ok_or_else(|| self.error())?;
+                            self.at += 4;
+                            // Characters past U+FFFF come as a high surrogate followed by a low one
+                            let low = self.text.get(self.at..self.at + 2)
+                                .filter(|escape| *escape == b"\\u")
+                                .and_then(|_| self.code_unit(self.at + 2));
+                            if let (0xD800..=0xDBFF, Some(low @ 0xDC00..=0xDFFF)) = (code, low) {
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                                self.at += 6;
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        },
+                        c => c as char,
+                    };
+                    res.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                c => res.push(c),
+            }
+        }
+    }
+}
+
+fn write_string(f : &mut fmt::Formatter<'_>, s : &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Display for Json {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) if n.fract() == 0. && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write_string(f, s),
+            Self::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{item}", if i == 0 { "" } else { "," })?;
+                }
+                write!(f, "]")
+            },
+            Self::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    write!(f, "{}", if i == 0 { "" } else { "," })?;
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
@@ -1,5 +1,24 @@
 mod parser;
-pub use parser::parse;
+pub use parser::{parse, parse_file, assemble, assemble_file};
+
+mod program;
+pub use program::{Program, Statement, Symbol, SymbolKind};
+
+mod listing;
+pub use listing::listing;
+
+mod map;
+pub use map::{map, map_symbols};
+
+pub mod debug;
+
+pub mod format;
+
+mod disasm;
+pub use disasm::{Decoded, decode, decode_all, disassemble, disassemble_source, render};
+
+pub mod source;
+pub mod stdlib;
 
 mod token;
 pub use token::{Token, Tokens, tokenize};
@@ -7,12 +26,29 @@ pub use token::{Token, Tokens, tokenize};
 mod expr;
 pub use expr::Expr;
 
+pub mod runtime;
+
+pub mod vm;
+
+pub mod coverage;
+pub mod debugger;
+pub mod gdb;
+pub mod dap;
+pub mod testing;
+pub mod machine;
+pub mod build;
+
+pub mod json;
+
 pub mod utils;
 
 #[cfg(test)]
 mod test;
 
 pub fn compile(code : &str) -> utils::Result<Vec<u8>> {
-    let (instructions, _) = parse(code)?;
-    Ok(instructions.into_iter().flat_map(|inst| inst.compile()).collect())
+    Ok(assemble(code)?.bytes())
+}
+
+pub fn compile_file(fpath : &str) -> utils::Result<Vec<u8>> {
+    Ok(assemble_file(fpath)?.bytes())
 }
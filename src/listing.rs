@@ -0,0 +1,71 @@
+use std::fmt::Write;
+
+use crate::{Expr, Program};
+
+const BYTES_PER_ROW : usize = 6;
+
+fn row(res : &mut String, address : Option<u16>, bytes : &[u8], number : Option<usize>, text : &str) {
+    let mut chunks = bytes.chunks(BYTES_PER_ROW);
+    let mut address = address;
+    let mut text = Some(text);
+    loop {
+        let chunk = chunks.next().unwrap_or(&[]);
+        let hex = chunk.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>().join(" ");
+        let addr = address.map_or(String::new(), |addr| format!("{addr:04X}"));
+        let num = number.filter(|_| text.is_some()).map_or(String::new(), |num| num.to_string());
+        writeln!(res, "{addr:4}  {hex:<17}  {num:>5}  {}", text.take().unwrap_or("")).unwrap();
+        address = address.map(|addr| addr.wrapping_add(chunk.len() as u16));
+
+        if chunks.len() == 0 {
+            break
+        }
+    }
+}
+
+/// Renders every source line with the address and bytes it assembled to, expansions indented below it,
+/// followed by the symbol table
+pub fn listing(program : &Program) -> String {
+    let mut res = String::new();
+    let mut statements = program.statements.iter().peekable();
+    let mut file = None;
+
+    for (i, line) in program.lines.iter().enumerate() {
+        if file != Some(&line.file) {
+            writeln!(res, "; {}", line.file).unwrap();
+            file = Some(&line.file);
+        }
+
+        let mut address = None;
+        let mut bytes = Vec::new();
+        let mut expansions = Vec::new();
+        while let Some(stmt) = statements.next_if(|stmt| stmt.line == i) {
+            address.get_or_insert(stmt.address);
+            match &stmt.expr {
+                Expr::Expansion(exprs) => {
+                    let stmt_bytes = stmt.bytes();
+                    let mut offset = 0;
+                    for (text, expr) in exprs.iter() {
+                        let len = expr.len() as usize;
+                        let start = offset.min(stmt_bytes.len());
+                        let end = (offset + len).min(stmt_bytes.len());
+                        expansions.push((stmt.address.wrapping_add(offset as u16), stmt_bytes[start..end].to_vec(), text));
+                        offset += len;
+                    }
+                },
+                _ => bytes.append(&mut stmt.bytes()),
+            }
+        }
+
+        row(&mut res, address, &bytes, Some(line.number), line.text.trim_end());
+        for (address, bytes, text) in expansions.into_iter() {
+            row(&mut res, Some(address), &bytes, None, &format!("        {text}"));
+        }
+    }
+
+    writeln!(res, "\n; Symbols").unwrap();
+    for symbol in program.symbols.iter() {
+        writeln!(res, "{:04X}  {:<8}  {}", symbol.address, symbol.kind.name(), symbol.name).unwrap();
+    }
+
+    res
+}
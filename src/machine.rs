@@ -0,0 +1,101 @@
+//! Calling routines in a program from Rust, for testing them with `cargo test`:
+//!
+//! ```ignore
+//! let mut machine = Machine::from_source(code)?;
+//! assert_eq!(machine.set_reg(Register::r0(), 10)?.call("fib")?.reg(Register::r0())?, 55);
+//! ```
+
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use smpl_core_common::Register;
+use crate::{Program, assemble, assemble_file, debug::DebugInfo, utils::{Error, Result}, vm::{STACK_TOP, Stop, Vm, device}};
+
+/// Instructions a routine can run before it's taken not to return
+pub const BUDGET : u64 = 1_000_000;
+
+/// A program loaded into the emulator, with the console, input, exit and timer devices at their
+/// usual addresses
+#[derive(Debug)]
+pub struct Machine {
+    pub vm : Vm,
+    identifiers : HashMap<String, u16>,
+    info : DebugInfo,
+    budget : u64,
+    console : Arc<Mutex<Vec<u8>>>,
+}
+
+impl Machine {
+    pub fn new(program : &Program) -> Result<Self> {
+        let console = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = Vm::from_program(program);
+        device::attach_default(&mut vm, console.clone())?;
+        Ok(Self { vm, identifiers: program.identifiers.clone(), info: DebugInfo::new(program), budget: BUDGET, console })
+    }
+
+    pub fn from_source(code : &str) -> Result<Self> {
+        Self::new(&assemble(code)?)
+    }
+
+    pub fn from_file(fpath : &str) -> Result<Self> {
+        Self::new(&assemble_file(fpath)?)
+    }
+
+    /// Sets the instructions each call can run, [`BUDGET`] by default
+    pub fn budget(&mut self, steps : u64) -> &mut Self {
+        self.budget = steps;
+        self
+    }
+
+    /// Address of a label or constant
+    pub fn address(&self, name : &str) -> Result<u16> {
+        self.identifiers.get(name).copied().ok_or_else(|| Error::NoSuchIdentifier(name.to_string()))
+    }
+
+    pub fn reg(&self, reg : Register) -> Result<u16> {
+        self.vm.reg(reg)
+    }
+
+    pub fn set_reg(&mut self, reg : Register, value : u16) -> Result<&mut Self> {
+        self.vm.set_reg(reg, value)?;
+        Ok(self)
+    }
+
+    /// `len` bytes of memory from `address`, as far as the end of memory
+    pub fn mem(&self, address : u16, len : usize) -> &[u8] {
+        let start = address as usize;
+        &self.vm.memory[start..(start + len).min(self.vm.memory.len())]
+    }
+
+    pub fn set_mem(&mut self, address : u16, bytes : &[u8]) -> &mut Self {
+        self.vm.load(bytes, address);
+        self
+    }
+
+    /// What's been printed to the console so far
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.console.lock().unwrap()).into_owned()
+    }
+
+    /// Where `address` is, as a label and an offset from it
+    fn location(&self, address : u16) -> String {
+        match self.info.label_before(address) {
+            Some(label) if label.address == address => format!("{address:#06X} ({})", label.name),
+            Some(label) => format!("{address:#06X} ({}+{})", label.name, address - label.address),
+            None => format!("{address:#06X}"),
+        }
+    }
+
+    /// Runs the routine at `name` on an empty stack until it returns
+    pub fn call(&mut self, name : &str) -> Result<&mut Self> {
+        self.vm.pc = self.address(name)?;
+        self.vm.sp = STACK_TOP;
+        let failure = match self.vm.run(Some(self.budget)) {
+            Ok(Stop::Returned) => return Ok(self),
+            Ok(Stop::Halted) => format!("halted at {}", self.location(self.vm.pc)),
+            Ok(Stop::Exit(code)) => format!("exited with {code} at {}", self.location(self.vm.pc)),
+            Ok(Stop::CycleLimit) => format!("still running after {} instructions, at {}", self.budget, self.location(self.vm.pc)),
+            Err(err) => format!("{err} at {}", self.location(self.vm.pc)),
+        };
+        Err(Error::External(format!("{name} didn't return: {failure}")))
+    }
+}
@@ -1,28 +1,255 @@
 use std::io::Write;
 
-use clap::Parser;
-use sasm_lib::{compile, utils::{Error, Result}};
+use clap::{Parser, Subcommand, ValueEnum};
+use sasm_lib::{assemble_file, coverage::Coverage, dap, debug::DebugInfo, debugger::{Debugger, HISTORY}, decode_all, disassemble_source, format::{self, Options}, gdb, listing, map, testing, utils::{Error, Result}, vm::{Observer, Stop, Vm, device, record, trace}};
 
 /// Assembler for SimpleASM
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command : Option<Command>,
+
     /// Path to file to assemble
-    in_path : String,
+    #[arg(required = true)]
+    in_path : Option<String>,
 
     /// Output file
     #[arg(short = 'o', default_value = "main.bin")]
     out_path : String,
+
+    /// Output file format
+    #[arg(long, value_enum, default_value_t = format::Format::Bin)]
+    format : format::Format,
+
+    /// Data bytes per record, for ihex and srec
+    #[arg(long, default_value_t = 16)]
+    record_len : usize,
+
+    /// Bits per ROM word, for memory initialisation formats
+    #[arg(long, default_value_t = 8)]
+    word_width : u8,
+
+    /// Put the first byte of 16 bit ROM words in the high half
+    #[arg(long)]
+    big_endian : bool,
+
+    /// ROM depth in words to pad memory initialisation formats to
+    #[arg(long)]
+    depth : Option<usize>,
+
+    /// Name of the array for c and rust, defaults to the output file name
+    #[arg(long)]
+    name : Option<String>,
+
+    /// Split the output over several ROM chips, written next to the output file. Chips start at the
+    /// program's first address, which the report shows.
+    #[arg(long, value_enum, requires = "chip_size")]
+    split : Option<Split>,
+
+    /// Size of every ROM chip in bytes, 0x and K suffixes allowed
+    #[arg(long, value_parser = parse_size)]
+    chip_size : Option<usize>,
+
+    /// Size of ROM banks in bytes, defaults to the chip size
+    #[arg(long, value_parser = parse_size)]
+    bank_size : Option<usize>,
+
+    /// Byte to pad ROM chips with
+    #[arg(long, default_value = "0xFF", value_parser = parse_byte)]
+    fill : u8,
+
+    /// Listing file, with the address and bytes of every source line
+    #[arg(short = 'l')]
+    listing_path : Option<String>,
+
+    /// Symbol map file, with the address, kind, size and section of every symbol
+    #[arg(long = "map")]
+    map_path : Option<String>,
+
+    /// Write debug info next to the output file, mapping addresses to source
+    #[arg(short = 'g')]
+    debug_info : bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Disassemble a binary
+    Disasm {
+        /// Path to file to disassemble
+        in_path : String,
+
+        /// Input file format, guessed from the extension by default
+        #[arg(long, value_enum)]
+        format : Option<format::Format>,
+
+        /// Address the binary is loaded at, ihex and srec files carry their own
+        #[arg(long, default_value = "0", value_parser = parse_address)]
+        base : u16,
+
+        /// Print source that assembles back into the same binary
+        #[arg(long)]
+        source : bool,
+
+        /// Address to follow control flow from when telling code from data, defaults to the base
+        #[arg(long = "entry", value_parser = parse_address)]
+        entries : Vec<u16>,
+    },
+
+    /// Assemble and run a program in the emulator
+    Run(RunArgs),
+
+    /// Assemble a program and step through it in the emulator
+    Debug {
+        /// Path to file to debug
+        in_path : String,
+
+        /// Map a device over memory, as name@address, like for run, except that input from stdin
+        /// is only there when it's mapped
+        #[arg(long = "device")]
+        devices : Vec<String>,
+
+        /// Replay a recording made by run
+        #[arg(long)]
+        replay : Option<String>,
+
+        /// Instructions to keep, to run backwards through
+        #[arg(long, default_value_t = HISTORY)]
+        history : usize,
+    },
+
+    /// Serve the Debug Adapter Protocol over stdio, for editors
+    Dap,
+
+    /// Assemble a program and let gdb drive it in the emulator, over TCP
+    Gdb {
+        /// Path to file to debug
+        in_path : String,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:1234")]
+        listen : String,
+
+        /// Map a device over memory, as name@address, like for run, except that input from stdin
+        /// is only there when it's mapped
+        #[arg(long = "device")]
+        devices : Vec<String>,
+
+        /// Replay a recording made by run
+        #[arg(long)]
+        replay : Option<String>,
+
+        /// Instructions to keep, to run backwards through
+        #[arg(long, default_value_t = HISTORY)]
+        history : usize,
+    },
+
+    /// Run the .test blocks in a program, exiting with 1 if any fail
+    Test {
+        /// Path to file to test
+        in_path : String,
+
+        /// Instructions each test can run before giving up
+        #[arg(long, default_value_t = 1_000_000)]
+        cycles : u64,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// Path to file to run
+    in_path : String,
+
+    /// Instructions to run before giving up
+    #[arg(long, default_value_t = 1_000_000)]
+    cycles : u64,
+
+    /// Map a device over memory, as name@address. The console, input, exit and timer devices
+    /// go at 0xFF00, 0xFF01, 0xFF03 and 0xFF04 unless any are given.
+    #[arg(long = "device")]
+    devices : Vec<String>,
+
+    /// Record every instruction run to a file, to replay later
+    #[arg(long, conflicts_with = "replay")]
+    record : Option<String>,
+
+    /// Replay a recording, checking the program does the same again
+    #[arg(long)]
+    replay : Option<String>,
+
+    /// Trace every instruction run to a file
+    #[arg(long)]
+    trace : Option<String>,
+
+    /// Format of the trace
+    #[arg(long, value_enum, default_value_t = TraceFormat::Text)]
+    trace_format : TraceFormat,
+
+    /// Write the registers and flags over time to a VCD file, for waveform viewers
+    #[arg(long)]
+    vcd : Option<String>,
+
+    /// Print how often every source line and label ran, once the program's done
+    #[arg(long)]
+    coverage : bool,
+
+    /// Write how often every source line and label ran to an lcov tracefile
+    #[arg(long)]
+    lcov : Option<String>,
+
+    /// Print the labels the program spent most instructions in, once it's done
+    #[arg(long)]
+    profile : bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TraceFormat {
+    /// A line per instruction
+    Text,
+    /// A JSON object per line
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Split {
+    /// Even bytes on one chip and odd bytes on the other
+    EvenOdd,
+    /// Consecutive banks, one per chip
+    Banks,
+}
+
+fn parse_size(arg : &str) -> std::result::Result<usize, String> {
+    let (arg, scale) = match arg.strip_suffix(['K', 'k']) {
+        Some(arg) => (arg, 1024),
+        None => (arg, 1),
+    };
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }.map(|size| size * scale).map_err(|err| err.to_string())
+}
+
+fn parse_byte(arg : &str) -> std::result::Result<u8, String> {
+    parse_size(arg).and_then(|byte| u8::try_from(byte).map_err(|err| err.to_string()))
+}
+
+fn parse_address(arg : &str) -> std::result::Result<u16, String> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }.map_err(|err| err.to_string())
 }
 
-fn compile_file(fpath : &str) -> Result<Vec<u8>> {
-    compile(&std::fs::read_to_string(fpath).map_err(|err| Error::External(err.to_string()))?)
+fn read_file(fpath : &str) -> Result<Vec<u8>> {
+    std::fs::read(fpath).map_err(|err| Error::External(err.to_string()))
 }
 
 fn write_file(fpath : &str, bytes : &[u8]) -> Result<()> {
     let mut fout = std::fs::OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(fpath)
         .map_err(|err| Error::External(err.to_string()))?;
     fout.write_all(bytes)
@@ -30,11 +257,183 @@ fn write_file(fpath : &str, bytes : &[u8]) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn disasm(in_path : &str, format : Option<format::Format>, base : u16, source : bool, entries : &[u16]) -> Result<()> {
+    let format = format.unwrap_or_else(|| format::Format::from_path(in_path));
+    let (base, bytes) = format.read(&read_file(in_path)?, base)?;
+    if source {
+        let entries = if entries.is_empty() { vec![base] } else { entries.to_vec() };
+        print!("{}", disassemble_source(&bytes, base, &entries)?);
+        return Ok(())
+    }
 
-    let bytes = compile_file(&args.in_path)?;
-    write_file(&args.out_path, &bytes)?;
+    for (address, decoded) in decode_all(&bytes, base)?.into_iter() {
+        let start = address.wrapping_sub(base) as usize;
+        let hex : Vec<String> = bytes[start..start + decoded.len() as usize].iter().map(|b| format!("{b:02X}")).collect();
+        println!("{address:04X}  {:<17}  {decoded}", hex.join(" "));
+    }
+    Ok(())
+}
 
+/// Maps `devices` over memory, or the default ones without `skip` if there are none
+fn attach_devices(vm : &mut Vm, devices : &[String], skip : &[&str]) -> Result<()> {
+    let devices = match devices {
+        [] => device::DEFAULT_MAP.iter()
+            .filter(|(name, _)| !skip.contains(name))
+            .map(|(name, address)| (name.to_string(), *address))
+            .collect(),
+        devices => devices.iter().map(|mapping| device::parse_mapping(mapping)).collect::<Result<Vec<_>>>()?,
+    };
+    for (name, address) in devices.into_iter() {
+        vm.attach(address, device::create(&name)?)?;
+    }
     Ok(())
 }
+
+fn replay(vm : &mut Vm, path : &Option<String>) -> Result<()> {
+    if let Some(path) = path {
+        vm.replay(record::read(&String::from_utf8_lossy(&read_file(path)?))?);
+    }
+    Ok(())
+}
+
+fn create_file(fpath : &str) -> Result<std::io::BufWriter<std::fs::File>> {
+    std::fs::File::create(fpath).map(std::io::BufWriter::new).map_err(|err| Error::External(err.to_string()))
+}
+
+fn run(args : &RunArgs) -> Result<()> {
+    let cycles = args.cycles;
+    let program = assemble_file(&args.in_path)?;
+    let mut vm = Vm::from_program(&program);
+    attach_devices(&mut vm, &args.devices, &[])?;
+    replay(&mut vm, &args.replay)?;
+
+    let mut recorder = args.record.as_ref().map(|path| record::Recorder::new(create_file(path)?, &vm)).transpose()?;
+    let format = match args.trace_format {
+        TraceFormat::Text => trace::Format::Text,
+        TraceFormat::Json => trace::Format::Json,
+    };
+    let mut tracer = args.trace.as_ref().map(|path| Ok::<_, Error>(trace::Tracer::new(create_file(path)?, format, &vm))).transpose()?;
+    let mut vcd = args.vcd.as_ref().map(|path| trace::Vcd::new(create_file(path)?, &vm)).transpose()?;
+    let mut coverage = Coverage::new();
+    let covering = args.coverage || args.lcov.is_some() || args.profile;
+
+    let mut observers : Vec<&mut dyn Observer> = Vec::new();
+    if let Some(recorder) = &mut recorder {
+        observers.push(recorder);
+    }
+    if let Some(tracer) = &mut tracer {
+        observers.push(tracer);
+    }
+    if let Some(vcd) = &mut vcd {
+        observers.push(vcd);
+    }
+    if covering {
+        observers.push(&mut coverage);
+    }
+
+    let stop = if observers.is_empty() { vm.run(Some(cycles)) } else { vm.run_observed(Some(cycles), &mut observers) };
+    eprint!("{}", vm.dump());
+
+    if covering {
+        let info = DebugInfo::new(&program);
+        if args.coverage {
+            eprint!("\n{}", coverage.report(&program, &info));
+        }
+        if args.profile {
+            eprint!("\n{}", coverage.profile(&info));
+        }
+        if let Some(path) = &args.lcov {
+            write_file(path, coverage.lcov(&info).as_bytes())?;
+        }
+    }
+
+    match stop? {
+        Stop::CycleLimit => Err(Error::External(format!("still running after {cycles} cycles"))),
+        Stop::Exit(code) => std::process::exit(code as i32),
+        _ => Ok(()),
+    }
+}
+
+fn debugger(in_path : &str, devices : &[String], replay_path : &Option<String>, history : usize) -> Result<Debugger> {
+    let program = assemble_file(in_path)?;
+    let mut vm = Vm::from_program(&program);
+    // Debugger commands come from stdin, so the program only gets it when input's mapped explicitly
+    attach_devices(&mut vm, devices, &["input"])?;
+    replay(&mut vm, replay_path)?;
+    vm.history.capacity = history;
+    Ok(Debugger::new(program, vm))
+}
+
+fn assemble(args : &Args, in_path : &str) -> Result<()> {
+    let program = assemble_file(in_path)?;
+    let options = Options {
+        record_len: args.record_len,
+        word_width: args.word_width,
+        big_endian: args.big_endian,
+        depth: args.depth,
+        name: args.name.clone().unwrap_or_else(|| {
+            std::path::Path::new(&args.out_path).file_stem().unwrap_or_default().to_string_lossy().to_string()
+        }),
+    };
+    match (args.split, args.chip_size) {
+        (Some(split), Some(chip_size)) => {
+            let split = match split {
+                Split::EvenOdd => format::split::Split::EvenOdd,
+                Split::Banks => format::split::Split::Banks(args.bank_size.unwrap_or(chip_size)),
+            };
+            let chips = format::split::split(&program.bytes(), program.base, split, chip_size, args.fill)?;
+            let out_path = std::path::Path::new(&args.out_path);
+            for chip in chips.iter() {
+                // main.bin becomes main.even.bin, main.bank0.bin and so on
+                let stem = out_path.file_stem().unwrap_or_default().to_string_lossy();
+                let chip_path = out_path.with_file_name(match out_path.extension() {
+                    Some(ext) => format!("{stem}.{}.{}", chip.name, ext.to_string_lossy()),
+                    None => format!("{stem}.{}", chip.name),
+                });
+                write_file(&chip_path.to_string_lossy(), &args.format.write(&chip.bytes, 0, &options)?)?;
+            }
+            print!("{}", format::split::report(&chips));
+        },
+        _ => write_file(&args.out_path, &args.format.write_program(&program, &options)?)?,
+    }
+
+    if let Some(listing_path) = &args.listing_path {
+        write_file(listing_path, listing(&program).as_bytes())?;
+    }
+
+    if let Some(map_path) = &args.map_path {
+        write_file(map_path, map(&program).as_bytes())?;
+    }
+
+    if args.debug_info {
+        let debug_path = std::path::Path::new(&args.out_path).with_extension("dbg");
+        write_file(&debug_path.to_string_lossy(), DebugInfo::new(&program).to_string().as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn test(in_path : &str, cycles : u64) -> Result<()> {
+    let outcomes = testing::run_file(in_path, cycles)?;
+    print!("{}", testing::report(&outcomes));
+    if !outcomes.iter().all(testing::Outcome::passed) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match &args.command {
+        Some(Command::Disasm { in_path, format, base, source, entries }) => disasm(in_path, *format, *base, *source, entries),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::Debug { in_path, devices, replay, history }) =>
+            debugger(in_path, devices, replay, *history)?.repl(std::io::stdin().lock(), std::io::stdout()),
+        Some(Command::Dap) => dap::serve(std::io::stdin().lock(), std::io::stdout()),
+        Some(Command::Gdb { in_path, listen, devices, replay, history }) =>
+            gdb::serve(&mut debugger(in_path, devices, replay, *history)?, listen),
+        Some(Command::Test { in_path, cycles }) => test(in_path, *cycles),
+        None => assemble(&args, args.in_path.as_deref().unwrap_or_default()),
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt::Write;
+
+use crate::{Program, Symbol};
+
+/// Renders symbols one `ADDR NAME KIND SIZE SECTION` line each. Sizes are in hex like addresses, and
+/// unknown sizes and sections are written as `-`.
+pub fn map_symbols(symbols : &[Symbol]) -> String {
+    let mut res = String::new();
+    for symbol in symbols.iter() {
+        writeln!(res, "{:04X} {} {} {} {}",
+            symbol.address,
+            symbol.name,
+            symbol.kind.name(),
+            symbol.size.map_or("-".to_string(), |size| format!("{size:04X}")),
+            symbol.section.as_deref().unwrap_or("-"),
+        ).unwrap();
+    }
+    res
+}
+
+/// Renders the symbol table of `program`, sorted by address
+pub fn map(program : &Program) -> String {
+    map_symbols(&program.symbols)
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use smpl_core_common::{Instruction, Value, Register};
-use crate::{Expr, Token, Tokens, tokenize, utils::{Error, Result}};
+use crate::{Expr, Program, Statement, Symbol, SymbolKind, Token, Tokens, tokenize, runtime, testing, source::{self, Line}, utils::{self, Error, Result}};
 
 fn parse_db_values(toks : &mut Tokens, ctx : &'static str) -> Result<Vec<i64>> {
     let mut values = Vec::new();
@@ -98,12 +98,38 @@ fn parse_one_c(op : Token, value : i64, _toks : &mut Tokens) -> Result<Expr> {
     }?))
 }
 
+fn parse_one_l(op : Token, label : String, toks : &mut Tokens) -> Result<Expr> {
+    use Token::*;
+    match op {
+        Call => Ok(Expr::CallC(label)),
+
+        AJmp | Jmp | Jeq | Jneq | Jlt | Jgt | Jleq | Jgeq | Jo | Jno => {
+            // Jumps only take registers, so the target goes through a scratch one
+            let Some(t) = toks.pop() else { return Err(Error::EOF("comma", "parse_one_l")) };
+            if t != Comma {
+                return Err(Error::UnexpectedToken(t, "parse_one_l"))
+            }
+
+            let Some(t) = toks.pop() else { return Err(Error::EOF("scratch register", "parse_one_l")) };
+            let Token::Register(scratch) = t else { return Err(Error::UnexpectedToken(t, "parse_one_l")) };
+            Instruction::movc2r(Value::word(0), scratch)?;
+
+            let relative = op != AJmp;
+            let Expr::Instruction(jump) = parse_one_r(op, scratch, toks)? else { unreachable!() };
+            Ok(Expr::Jump(label, scratch, relative, jump))
+        },
+
+        _ => Err(Error::UnexpectedToken(op, "parse_one_l")),
+    }
+}
+
 fn parse_one(op : Token, toks : &mut Tokens) -> Result<Expr> {
     let Some(t) = toks.pop() else { return Err(Error::EOF("value", "parse_one")) };
 
     match t {
         Token::Register(reg) => parse_one_r(op, reg, toks),
         Token::Number(value) => parse_one_c(op, value, toks),
+        Token::IdentifierRef(label) => parse_one_l(op, label, toks),
 
         _ => Err(Error::UnexpectedToken(t, "parse_one")),
     }
@@ -215,7 +241,58 @@ fn parse_two(op : Token, toks : &mut Tokens) -> Result<Expr> {
     }
 }
 
-fn parse_toks(t : Token, toks : &mut Tokens) -> Result<Expr> {
+fn parse_arith(op : Token, toks : &mut Tokens) -> Result<Expr> {
+    let (t1, t2) = parse_comma(toks, "parse_arith")?;
+    let Token::Register(dest) = t2 else { return Err(Error::UnexpectedToken(t2, "parse_arith")) };
+    let (dest_idx, byte) = utils::register_index(dest)?;
+    let prefix = if byte { "rb" } else { "r" };
+    let mut lines = Vec::new();
+
+    // Save the argument registers the result doesn't overwrite
+    if dest_idx != 1 { lines.push("push r1".to_string()) }
+    if dest_idx != 0 { lines.push("push r0".to_string()) }
+
+    // Permute through the stack so any source and destination registers work
+    match t1 {
+        Token::Register(src) => {
+            let (src_idx, src_byte) = utils::register_index(src)?;
+            if src_byte != byte {
+                return Err(Error::UnexpectedToken(Token::Register(src), "parse_arith"))
+            }
+            lines.push(format!("push r{src_idx}"));
+            lines.push(format!("push r{dest_idx}"));
+            lines.push("pop r0".to_string());
+            lines.push("pop r1".to_string());
+        },
+        Token::Number(value) => {
+            if dest_idx != 0 { lines.push(format!("mov r{dest_idx}, r0")) }
+            lines.push(format!("mov {value}, {prefix}1"));
+        },
+        _ => return Err(Error::UnexpectedToken(t1, "parse_arith")),
+    }
+
+    lines.push(format!("call {}", runtime::routine(&op, byte)));
+
+    let result_idx = if op == Token::Mod { 1 } else { 0 };
+    if result_idx != dest_idx { lines.push(format!("mov {prefix}{result_idx}, {prefix}{dest_idx}")) }
+
+    if dest_idx != 0 { lines.push("pop r0".to_string()) }
+    if dest_idx != 1 { lines.push("pop r1".to_string()) }
+
+    expand(lines)
+}
+
+fn expand(lines : Vec<String>) -> Result<Expr> {
+    let mut exprs = Vec::new();
+    for line in lines.into_iter() {
+        for expr in parse_to_exprs(&line)?.into_iter() {
+            exprs.push((line.clone(), expr));
+        }
+    }
+    Ok(Expr::Expansion(exprs))
+}
+
+pub(crate) fn parse_toks(t : Token, toks : &mut Tokens) -> Result<Expr> {
     use Token::*;
     match t {
         IdentifierDef(ident) => Ok(Expr::IdentifierDef(ident)),
@@ -235,6 +312,9 @@ fn parse_toks(t : Token, toks : &mut Tokens) -> Result<Expr> {
         Add | Sub | And | Or | Shl | Shr | Shre | Cmp
             => parse_two(t, toks),
 
+        Mul | Div | Mod
+            => parse_arith(t, toks),
+
         _ => Err(Error::UnexpectedToken(t, "parse_toks")),
     }
 }
@@ -250,24 +330,166 @@ fn parse_to_exprs(code : &str) -> Result<Vec<Expr>> {
     Ok(res)
 }
 
-pub fn parse(code : &str) -> Result<(Vec<Instruction>, HashMap<String, u16>)> {
-    let exprs = parse_to_exprs(code)?;
-    let mut identifiers = HashMap::new();
-    let mut offset = 0;
-    for expr in exprs.iter() {
-        if let Expr::IdentifierDef(ident) = expr {
-            identifiers.insert(ident.clone(), offset);
-        };
+/// A lone number, written like in an instruction
+pub(crate) fn parse_number(text : &str) -> Option<i64> {
+    let mut toks = tokenize(text).ok()?;
+    match (toks.pop(), toks.pop()) {
+        (Some(Token::Number(value)), None) => Some(value),
+        _ => None,
+    }
+}
+
+fn parse_directive(code : &str) -> Result<Expr> {
+    let (name, args) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+    let args : Vec<&str> = args.split(',').map(str::trim).filter(|arg| !arg.is_empty()).collect();
+
+    let word = |arg : &str| -> Result<u16> {
+        let value = parse_number(arg).ok_or(Error::Directive(code.to_string()))?;
+        if !(-0x8000..=0xFFFF).contains(&value) {
+            return Err(Error::NumberTooLarge(value, "word"))
+        }
+        Ok(value as u16)
+    };
+    let ident = |arg : &str| -> Result<String> {
+        if arg.is_empty() || arg.starts_with(|c : char| c.is_ascii_digit()) || !arg.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(Error::Directive(code.to_string()))
+        }
+        Ok(arg.to_string())
+    };
 
-        offset += expr.len();
+    match (name, args.as_slice()) {
+        (".equ", [name, value]) => Ok(Expr::Constant(ident(name)?, word(value)?)),
+        (".section", [name]) => Ok(Expr::Section(ident(name)?)),
+        (".org", [address]) => Ok(Expr::Org(word(address)?)),
+
+        _ => Err(Error::Directive(code.to_string())),
     }
-    
+}
+
+pub(crate) fn parse_lines(lines : &[Line]) -> Result<Vec<(usize, Expr)>> {
     let mut res = Vec::new();
+
+    // Statements end with their line, unless they're missing operands
+    let mut pending = String::new();
+    let mut start = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if pending.is_empty() {
+            start = i;
+
+            if line.code.trim_start().starts_with('.') {
+                res.push((i, parse_directive(line.code.trim())?));
+                continue
+            }
+        }
+        pending.push_str(&line.code);
+        pending.push('\n');
+
+        match parse_to_exprs(&pending) {
+            Ok(exprs) => {
+                res.extend(exprs.into_iter().map(|expr| (start, expr)));
+                pending.clear();
+            },
+            Err(Error::EOF(_, _)) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    res.extend(parse_to_exprs(&pending)?.into_iter().map(|expr| (start, expr)));
+
+    Ok(res)
+}
+
+/// `offset` moved past `len` bytes, which have to fit in memory
+fn advance(offset : u32, len : u16) -> Result<u32> {
+    match offset + len as u32 {
+        end if end > 0x10000 => Err(Error::External(format!("{len} bytes at {offset:#06X} go past the end of memory"))),
+        end => Ok(end),
+    }
+}
+
+/// `offset` as an address, which it can only not be once memory's full
+fn to_address(offset : u32) -> Result<u16> {
+    u16::try_from(offset).map_err(|_| Error::External("nothing fits after the end of memory".to_string()))
+}
+
+pub(crate) fn assemble_lines(mut lines : Vec<Line>) -> Result<Program> {
+    testing::strip(&mut lines)?;
+    let mut exprs = parse_lines(&lines)?;
+    runtime::link(&mut lines, &mut exprs)?;
+
+    let mut identifiers = HashMap::new();
+    let mut symbols = vec![Symbol::new("text", 0, SymbolKind::Section, Some("text"))];
+    let mut section = symbols[0].name.clone();
+    let mut base = 0;
+    let mut emitted = false;
     let mut offset = 0;
-    for expr in exprs.iter() {
-        res.append(&mut expr.to_instructions(&identifiers, offset)?);
+    for (_, expr) in exprs.iter_mut() {
+        match expr {
+            Expr::IdentifierDef(ident) => {
+                identifiers.insert(ident.clone(), to_address(offset)?);
+                symbols.push(Symbol::new(ident, to_address(offset)?, SymbolKind::Label, Some(section.as_str())));
+            },
+            Expr::Constant(ident, value) => {
+                identifiers.insert(ident.clone(), *value);
+                symbols.push(Symbol::new(ident, *value, SymbolKind::Constant, None));
+            },
+            Expr::Section(name) => {
+                section = name.clone();
+                symbols.push(Symbol::new(name, to_address(offset)?, SymbolKind::Section, Some(name.as_str())));
+            },
+            Expr::Org(address) if !emitted => {
+                // Nothing's been placed yet, so everything so far moves along
+                base = *address;
+                offset = *address as u32;
+                for symbol in symbols.iter_mut().filter(|symbol| symbol.kind != SymbolKind::Constant) {
+                    symbol.address = *address;
+                    if symbol.kind == SymbolKind::Label {
+                        identifiers.insert(symbol.name.clone(), *address);
+                    }
+                }
+            },
+            Expr::Org(address) => {
+                if (*address as u32) < offset {
+                    return Err(Error::Directive(format!(".org {:#06X} is before {offset:#06X}", *address)))
+                }
+                *expr = Expr::DB(vec![0; (*address as u32 - offset) as usize]);
+            },
+            _ => (),
+        };
 
-        offset += expr.len();
+        emitted |= expr.len() > 0;
+        offset = advance(offset, expr.len())?;
     }
-    Ok((res, identifiers))
+    Symbol::fill_sizes(&mut symbols, offset);
+
+    let mut statements = Vec::new();
+    let mut offset = base as u32;
+    for (line, expr) in exprs.into_iter() {
+        // Only things taking no room can be at 0x10000, once memory's full
+        let address = offset as u16;
+        let instructions = expr.to_instructions(&identifiers, address)?;
+        let len = expr.len();
+        statements.push(Statement { line, address, expr, instructions });
+
+        offset = advance(offset, len)?;
+    }
+    Ok(Program { lines, base, statements, identifiers, symbols })
+}
+
+pub fn assemble(code : &str) -> Result<Program> {
+    assemble_lines(source::load("<input>", None, code)?)
+}
+
+pub fn assemble_file(fpath : &str) -> Result<Program> {
+    let code = std::fs::read_to_string(fpath).map_err(|err| Error::External(err.to_string()))?;
+    assemble_lines(source::load(fpath, std::path::Path::new(fpath).parent(), &code)?)
+}
+
+pub fn parse(code : &str) -> Result<(Vec<Instruction>, HashMap<String, u16>)> {
+    let program = assemble(code)?;
+    Ok((program.instructions(), program.identifiers))
+}
+
+pub fn parse_file(fpath : &str) -> Result<(Vec<Instruction>, HashMap<String, u16>)> {
+    let program = assemble_file(fpath)?;
+    Ok((program.instructions(), program.identifiers))
 }
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use smpl_core_common::Instruction;
+use crate::{Expr, source::Line};
+
+/// An expression, where it ended up, and what it compiled to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub line : usize,
+    pub address : u16,
+    pub expr : Expr,
+    pub instructions : Vec<Instruction>,
+}
+
+impl Statement {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.instructions.iter().flat_map(|inst| inst.compile()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    Section,
+    Label,
+    Constant,
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Section => "section",
+            Self::Label => "label",
+            Self::Constant => "constant",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Self> {
+        [Self::Section, Self::Label, Self::Constant].into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name : String,
+    pub address : u16,
+    pub kind : SymbolKind,
+    pub size : Option<u16>,
+    pub section : Option<String>,
+}
+
+impl Symbol {
+    pub fn new(name : &str, address : u16, kind : SymbolKind, section : Option<&str>) -> Self {
+        Self { name: name.to_string(), address, kind, size: None, section: section.map(str::to_string) }
+    }
+
+    /// Sizes every section and label as the distance to the next one, and sorts them all by address.
+    /// `end` is where the program ends, which may be the end of memory.
+    pub(crate) fn fill_sizes(symbols : &mut [Symbol], end : u32) {
+        symbols.sort_by(|a, b| (a.address, a.kind, &a.name).cmp(&(b.address, b.kind, &b.name)));
+
+        let starts = |kinds : &[SymbolKind]| -> Vec<u32> {
+            symbols.iter()
+                .filter(|symbol| kinds.contains(&symbol.kind))
+                .map(|symbol| symbol.address as u32)
+                .chain([end])
+                .collect()
+        };
+        let sections = starts(&[SymbolKind::Section]);
+        let labels = starts(&[SymbolKind::Section, SymbolKind::Label]);
+
+        for symbol in symbols.iter_mut() {
+            let starts = match symbol.kind {
+                SymbolKind::Section => &sections,
+                SymbolKind::Label => &labels,
+                SymbolKind::Constant => continue,
+            };
+            let address = symbol.address as u32;
+            symbol.size = starts.iter().find(|start| **start > address).and_then(|start| u16::try_from(start - address).ok());
+        }
+    }
+}
+
+/// An assembled program, along with the source it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub lines : Vec<Line>,
+    pub base : u16,
+    pub statements : Vec<Statement>,
+    pub identifiers : HashMap<String, u16>,
+    pub symbols : Vec<Symbol>,
+}
+
+impl Program {
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.statements.iter().flat_map(|stmt| stmt.instructions.iter().copied()).collect()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.statements.iter().flat_map(Statement::bytes).collect()
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashSet;
+
+use crate::{Expr, Token, parser::parse_lines, source::{self, Line}, utils::{Error, Result}};
+
+/// Source of the routines behind `mul`, `div` and `mod`, see it for the calling convention
+pub const SOURCE : &str = include_str!("runtime/arith.sasm");
+
+/// Entry points defined in [`SOURCE`]
+pub const ROUTINES : [&str; 4] = ["sasm_mul", "sasm_div", "sasm_mulb", "sasm_divb"];
+
+/// Routine a `mul`, `div` or `mod` of the given width calls into
+pub(crate) fn routine(op : &Token, byte : bool) -> &'static str {
+    match (op, byte) {
+        (Token::Mul, false) => "sasm_mul",
+        (Token::Mul, true) => "sasm_mulb",
+        (_, false) => "sasm_div",
+        (_, true) => "sasm_divb",
+    }
+}
+
+/// Appends the runtime to `exprs` if they reference any of its routines without defining them,
+/// failing if they define any other label the runtime does
+pub(crate) fn link(lines : &mut Vec<Line>, exprs : &mut Vec<(usize, Expr)>) -> Result<()> {
+    let defined : HashSet<&str> = exprs.iter().filter_map(|(_, expr)| match expr {
+        Expr::IdentifierDef(ident) => Some(ident.as_str()),
+        _ => None,
+    }).collect();
+
+    let used = exprs.iter()
+        .flat_map(|(_, expr)| expr.references())
+        .any(|ident| ROUTINES.contains(&ident) && !defined.contains(ident));
+
+    if used {
+        let offset = lines.len();
+        let mut runtime = source::load("<runtime>", None, SOURCE)?;
+        let runtime_exprs = parse_lines(&runtime)?;
+        for (_, expr) in runtime_exprs.iter() {
+            if let Expr::IdentifierDef(ident) = expr {
+                if defined.contains(ident.as_str()) {
+                    return Err(Error::External(format!("{ident} is defined by the program and by the runtime behind mul, div and mod")))
+                }
+            }
+        }
+        exprs.extend(runtime_exprs.into_iter().map(|(line, expr)| (offset + line, expr)));
+        lines.append(&mut runtime);
+    }
+    Ok(())
+}
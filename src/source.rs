@@ -0,0 +1,111 @@
+use std::{collections::HashSet, path::{Path, PathBuf}};
+
+use crate::{stdlib, utils::{Error, Result}};
+
+/// A line of source, after `.include`s have been expanded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub file : String,
+    pub number : usize,
+    pub text : String,
+    pub code : String,
+}
+
+/// Blanks out comments, keeping every line where it was
+pub fn strip_comments(code : &str) -> String {
+    let mut res = String::with_capacity(code.len());
+    let mut chars = code.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => { in_string = !in_string; res.push(c) },
+            '\n' => { in_string = false; res.push(c) },
+
+            '/' if !in_string && chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            },
+
+            '/' if !in_string && chars.peek() == Some(&'*') => {
+                chars.next();
+                res.push_str("  ");
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    res.push(if c == '\n' { '\n' } else { ' ' });
+                    if prev == '*' && c == '/' {
+                        break
+                    }
+                    prev = c;
+                }
+            },
+
+            _ => res.push(c),
+        }
+    }
+
+    res
+}
+
+struct Loader<'a> {
+    seen : HashSet<String>,
+    lines : Vec<Line>,
+    include_dirs : &'a [PathBuf],
+}
+
+impl Loader<'_> {
+    fn load(&mut self, file : &str, dir : Option<&Path>, code : &str) -> Result<()> {
+        let stripped = strip_comments(code);
+        for (i, (text, stripped)) in code.lines().zip(stripped.lines()).enumerate() {
+            let directive = stripped.trim().strip_prefix(".include");
+            self.lines.push(Line {
+                file: file.to_string(),
+                number: i + 1,
+                text: text.to_string(),
+                code: if directive.is_some() { String::new() } else { stripped.to_string() },
+            });
+
+            if let Some(target) = directive {
+                self.include(target.trim(), dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, target : &str, dir : Option<&Path>) -> Result<()> {
+        if let Some(name) = target.strip_prefix('<').and_then(|target| target.strip_suffix('>')) {
+            let code = stdlib::get(name).ok_or(Error::Include(target.to_string()))?;
+            if self.seen.insert(target.to_string()) {
+                self.load(target, None, code)?;
+            }
+        } else if let Some(name) = target.strip_prefix('"').and_then(|target| target.strip_suffix('"')) {
+            let mut path = dir.map_or(Path::new(name).to_path_buf(), |dir| dir.join(name));
+            if !path.is_file() {
+                path = self.include_dirs.iter().map(|dir| dir.join(name)).find(|path| path.is_file()).unwrap_or(path);
+            }
+            let code = std::fs::read_to_string(&path)
+                .map_err(|err| Error::Include(format!("{target}: {err}")))?;
+            if self.seen.insert(path.to_string_lossy().to_string()) {
+                self.load(&path.to_string_lossy(), path.parent(), &code)?;
+            }
+        } else {
+            return Err(Error::Include(target.to_string()))
+        }
+        Ok(())
+    }
+}
+
+/// Splits `code` into lines, expanding `.include <std/...>` from the bundled standard library and
+/// `.include "path"` relative to `dir`. Every file is included at most once.
+pub fn load(file : &str, dir : Option<&Path>, code : &str) -> Result<Vec<Line>> {
+    load_with(file, dir, &[], code)
+}
+
+/// Like [`load`], looking for `.include "path"` in `include_dirs`, in order, when it isn't
+/// relative to the including file
+pub fn load_with(file : &str, dir : Option<&Path>, include_dirs : &[PathBuf], code : &str) -> Result<Vec<Line>> {
+    let mut loader = Loader { seen: HashSet::from([file.to_string()]), lines: Vec::new(), include_dirs };
+    loader.load(file, dir, code)?;
+    Ok(loader.lines)
+}
@@ -0,0 +1,11 @@
+/// Files of the standard library, available through `.include <std/...>`
+pub const FILES : [(&str, &str); 4] = [
+    ("std/mem.sasm", include_str!("std/mem.sasm")),
+    ("std/string.sasm", include_str!("std/string.sasm")),
+    ("std/fmt.sasm", include_str!("std/fmt.sasm")),
+    ("std/heap.sasm", include_str!("std/heap.sasm")),
+];
+
+pub fn get(path : &str) -> Option<&'static str> {
+    FILES.iter().find(|(name, _)| *name == path).map(|(_, code)| *code)
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use smpl_core_common::{Instruction, Register, Value};
-use crate::{parse, utils::Error};
+use crate::{assemble, parse, utils::Error};
 
 macro_rules! case {
     ($ident:ident, $code:literal, $result:expr) => {
@@ -143,3 +143,772 @@ case!(callr, "call r0", Ok((vec![Instruction::callr(Register::r0()).unwrap()], H
 case!(int, "int r0", Ok((vec![Instruction::int(Register::r0()).unwrap()], HashMap::new())));
 case!(sti, "sti r0", Ok((vec![Instruction::sti(Register::r0()).unwrap()], HashMap::new())));
 case!(cli, "cli", Ok((vec![Instruction::cli()], HashMap::new())));
+
+#[test]
+fn jump_label() {
+    let jump = Instruction::jmp(Register::r5()).unwrap();
+    let len = Instruction::movc2r(Value::word(0), Register::r5()).unwrap().len() + jump.len();
+    assert_eq!(parse("l: jmp l, r5"), Ok((
+        vec![Instruction::movc2r(Value::word(0u16.wrapping_sub(len)), Register::r5()).unwrap(), jump],
+        HashMap::from([("l".to_string(), 0)]),
+    )));
+}
+
+#[test]
+fn ajmp_label() {
+    let (instructions, _) = parse("nop\nl: ajmp l, r5").unwrap();
+    assert_eq!(instructions[1..], [
+        Instruction::movc2r(Value::word(Instruction::nop().len()), Register::r5()).unwrap(),
+        Instruction::ajmp(Register::r5()).unwrap(),
+    ]);
+}
+
+case!(callc_label, "l: call l", Ok((vec![Instruction::callc(Value::word(0)).unwrap()], HashMap::from([("l".to_string(), 0)]))));
+
+case!(no_runtime, "mov 0xF3, rb0", Ok((vec![Instruction::movc2r(Value::byte(0xF3), Register::rb0()).unwrap()], HashMap::new())));
+
+#[test]
+fn mul_word() {
+    let (instructions, identifiers) = parse("mul r2, r3").unwrap();
+    assert_eq!(instructions[..10], [
+        Instruction::push(Register::r1()).unwrap(),
+        Instruction::push(Register::r0()).unwrap(),
+        Instruction::push(Register::r2()).unwrap(),
+        Instruction::push(Register::r3()).unwrap(),
+        Instruction::pop(Register::r0()).unwrap(),
+        Instruction::pop(Register::r1()).unwrap(),
+        Instruction::callc(Value::word(identifiers["sasm_mul"])).unwrap(),
+        Instruction::movr2r(Register::r0(), Register::r3()).unwrap(),
+        Instruction::pop(Register::r0()).unwrap(),
+        Instruction::pop(Register::r1()).unwrap(),
+    ]);
+}
+
+#[test]
+fn mod_byte_into_rb1() {
+    let (instructions, identifiers) = parse("mod 7, rb1").unwrap();
+    assert_eq!(instructions[..5], [
+        Instruction::push(Register::r0()).unwrap(),
+        Instruction::movr2r(Register::r1(), Register::r0()).unwrap(),
+        Instruction::movc2r(Value::byte(7), Register::rb1()).unwrap(),
+        Instruction::callc(Value::word(identifiers["sasm_divb"])).unwrap(),
+        Instruction::pop(Register::r0()).unwrap(),
+    ]);
+}
+
+#[test]
+fn runtime_linked_once() {
+    let (_, identifiers) = parse("mul r0, r1\ndiv rb2, rb3").unwrap();
+    for routine in crate::runtime::ROUTINES {
+        assert!(identifiers.contains_key(routine));
+    }
+}
+
+#[test]
+fn runtime_label_clash() {
+    let err = Error::External("sasm_mul is defined by the program and by the runtime behind mul, div and mod".to_string());
+    assert_eq!(parse("sasm_mul: ret\ndiv r0, r1"), Err(err));
+}
+
+case!(mul_mixed_width, "mul rb0, r1", Err(Error::UnexpectedToken(crate::Token::Register(Register::rb0()), "parse_arith")));
+
+case!(db_then_code, "db 0xF3\nnop", Ok((vec![Instruction::db(0xF3), Instruction::nop()], HashMap::new())));
+case!(db_continued, "db 0xF3,\n0x37", Ok((vec![Instruction::db(0xF3), Instruction::db(0x37)], HashMap::new())));
+
+#[test]
+fn stdlib_assembles() {
+    for (name, _) in crate::stdlib::FILES {
+        let (instructions, _) = parse(&format!(".include <{name}>")).unwrap();
+        assert!(!instructions.is_empty(), "{name}");
+    }
+}
+
+#[test]
+fn stdlib_symbols() {
+    let (_, identifiers) = parse(".include <std/mem.sasm>\n.include <std/string.sasm>\n.include <std/fmt.sasm>\n.include <std/heap.sasm>").unwrap();
+    for symbol in ["memcpy", "memset", "memcmp", "strlen", "strcmp", "utoa", "xtoa", "heap_init", "malloc", "free"] {
+        assert!(identifiers.contains_key(symbol), "{symbol}");
+    }
+}
+
+#[test]
+fn include_once() {
+    let once = parse(".include <std/mem.sasm>").unwrap();
+    assert_eq!(parse(".include <std/mem.sasm>\n.include <std/mem.sasm>"), Ok(once));
+}
+
+case!(include_missing, ".include <std/nope.sasm>", Err(Error::Include("<std/nope.sasm>".to_string())));
+
+#[test]
+fn statement_lines() {
+    let program = assemble("foo:\n  db 1,\n     2\nnop").unwrap();
+    let lines : Vec<(usize, u16)> = program.statements.iter().map(|stmt| (stmt.line, stmt.address)).collect();
+    assert_eq!(lines, vec![(0, 0), (1, 0), (3, 2)]);
+}
+
+#[test]
+fn listing() {
+    let listing = crate::listing(&assemble("foo: nop\n\nmul r2, r3").unwrap());
+    let lines : Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[0], "; <input>");
+    assert!(lines[1].starts_with("0000  ") && lines[1].ends_with("    1  foo: nop"));
+    assert!(lines[3].ends_with("    3  mul r2, r3"));
+    assert!(lines[4].ends_with("        push r1"));
+    assert!(listing.contains("; <runtime>"));
+    assert!(listing.contains("\n0000  label     foo\n"));
+}
+
+case!(equ, ".equ answer, 0x2A\nmov answer, r0", Ok((
+    vec![Instruction::movc2r(Value::word(0x2A), Register::r0()).unwrap()],
+    HashMap::from([("answer".to_string(), 0x2A)]),
+)));
+case!(directive_err, ".equ 4, 2", Err(Error::Directive(".equ 4, 2".to_string())));
+
+#[test]
+fn org() {
+    let program = assemble("start:\n.org 0x100\ndb 1\n.org 0x104\nend: db 2").unwrap();
+    assert_eq!(program.base, 0x100);
+    assert_eq!(program.bytes(), vec![1, 0, 0, 0, 2]);
+    assert_eq!(program.identifiers, HashMap::from([("start".to_string(), 0x100), ("end".to_string(), 0x104)]));
+}
+
+case!(org_backwards, "db 1, 2\n.org 1", Err(Error::Directive(".org 0x0001 is before 0x0002".to_string())));
+case!(org_past_end, ".org 0xFFFF\ndw 1", Err(Error::External("2 bytes at 0xFFFF go past the end of memory".to_string())));
+
+#[test]
+fn org_last_byte() {
+    let program = assemble(".org 0xFFFF\nlast: db 1").unwrap();
+    assert_eq!((program.base, program.bytes()), (0xFFFF, vec![1]));
+    assert_eq!(program.identifiers, HashMap::from([("last".to_string(), 0xFFFF)]));
+    assert_eq!(assemble(".org 0xFFFF\ndb 1\nend:").unwrap_err(), Error::External("nothing fits after the end of memory".to_string()));
+}
+
+#[test]
+fn map() {
+    let program = assemble(".equ size, 3\nfoo: db 1, 2\n.section data\nbar: db 3\nbaz:").unwrap();
+    assert_eq!(crate::map(&program), [
+        "0000 text section 0002 text",
+        "0000 foo label 0002 text",
+        "0002 data section 0001 data",
+        "0002 bar label 0001 data",
+        "0003 baz label - data",
+        "0003 size constant - -",
+        "",
+    ].join("\n"));
+}
+
+#[test]
+fn debug_info() {
+    use crate::debug::DebugInfo;
+
+    let program = assemble("foo: nop\n  mul r2, r3").unwrap();
+    let info = DebugInfo::new(&program);
+    assert_eq!(info.files, vec!["<input>".to_string(), "<runtime>".to_string()]);
+
+    let nop = &info.lines[0];
+    assert_eq!((nop.address, nop.file, nop.line, nop.column, nop.expansion.as_deref()), (0, 0, 1, 6, None));
+    let push = info.line_at(nop.len).unwrap();
+    assert_eq!((push.line, push.column, push.expansion.as_deref()), (2, 3, Some("push r1")));
+    assert_eq!(info.addresses_of("<input>", 2)[0], nop.len);
+    assert!(info.addresses_of("input>", 2).is_empty());
+
+    assert_eq!(DebugInfo::parse(&info.to_string()), Ok(info));
+}
+
+const DISASM_CODE : &str = "mov 0x0CF3, r0\nmov 0x0D, rb2\nadd rb0, rb1\nsub r0, r2\nmov rb0, [r3]\nmov [r3], rb4\nshl 3, r1\npush r1\npop r2\ncall 0x1234\njmp r5\nnot r6\ncmp 7, rb7\nret\ncli";
+
+#[test]
+fn disassemble() {
+    let (instructions, _) = parse(DISASM_CODE).unwrap();
+    let bytes = crate::compile(DISASM_CODE).unwrap();
+    let disassembled = crate::disassemble(&bytes, 0x100).unwrap();
+    assert_eq!(disassembled.iter().map(|(_, inst)| *inst).collect::<Vec<_>>(), instructions);
+    assert_eq!(disassembled[0].0, 0x100);
+    assert_eq!(disassembled[1].0, 0x100 + instructions[0].len());
+    assert!(crate::disassemble(&bytes, 0xFFFF).is_err());
+}
+
+#[test]
+fn render() {
+    let (instructions, _) = parse(DISASM_CODE).unwrap();
+    let rendered : Vec<String> = instructions.iter().map(crate::render).collect();
+    assert_eq!(rendered[0], "mov 0x0CF3, r0");
+    assert_eq!(rendered[4], "mov rb0, [r3]");
+    assert_eq!(parse(&rendered.join("\n")).unwrap().0, instructions);
+    assert_eq!(crate::render(&Instruction::db(0xF3)), "db 0xF3");
+}
+
+fn assert_round_trip(code : &str) {
+    let program = assemble(code).unwrap();
+    let bytes = program.bytes();
+    let source = crate::disassemble_source(&bytes, program.base, &[program.base]).unwrap();
+    let reassembled = assemble(&source).unwrap();
+    assert_eq!((reassembled.base, reassembled.bytes()), (program.base, bytes), "{source}");
+}
+
+#[test]
+fn round_trip_examples() {
+    assert_round_trip(include_str!("../examples/basic.sasm"));
+    assert_round_trip(&format!(".org 0x0400\n{}", include_str!("../examples/basic.sasm")));
+    assert_round_trip(crate::runtime::SOURCE);
+    for (name, _) in crate::stdlib::FILES {
+        assert_round_trip(&format!("call memcpy\n.include <{name}>\n.include <std/mem.sasm>"));
+    }
+}
+
+#[test]
+fn recovered_labels() {
+    let source = crate::disassemble_source(&crate::compile("loop: call sub\njmp loop, r5\nsub: ret").unwrap(), 0, &[0]).unwrap();
+    let lines : Vec<&str> = source.lines().collect();
+    assert_eq!(lines[0], "L_0000:");
+    assert!(lines[1].starts_with("    call L_"));
+    assert_eq!(lines[2], "    jmp L_0000, r5");
+    assert!(lines[3].starts_with("L_") && lines[4] == "    ret");
+}
+
+#[test]
+fn ihex() {
+    use crate::format::{Format, Options};
+    let hex = Format::Ihex.write(&[0x01, 0x02, 0x03], 0x0400, &Options { record_len: 2, ..Default::default() }).unwrap();
+    assert_eq!(String::from_utf8(hex.clone()).unwrap(), ":020400000102F7\n:0104020003F6\n:00000001FF\n");
+    assert_eq!(Format::Ihex.read(&hex, 0), Ok((0x0400, vec![0x01, 0x02, 0x03])));
+    assert!(Format::Ihex.read(b":020400000102F8\n", 0).is_err());
+    assert_eq!(Format::Ihex.read(b":01FFFF000100\n", 0), Ok((0xFFFF, vec![0x01])));
+    assert!(Format::Ihex.read(b":02FFFF000102FD\n", 0).is_err());
+    assert!(Format::Ihex.write(&[0], 0, &Options { record_len: 0, ..Default::default() }).is_err());
+    assert!(Format::Ihex.write(&[0, 0], 0xFFFF, &Options::default()).is_err());
+}
+
+#[test]
+fn srec() {
+    use crate::format::{Format, Options};
+    let program = assemble(&format!(".org 0x0400\n{}", include_str!("../examples/basic.sasm"))).unwrap();
+    let srec = Format::Srec.write(&program.bytes(), program.base, &Options { record_len: 5, ..Default::default() }).unwrap();
+    let text = String::from_utf8(srec.clone()).unwrap();
+    assert!(text.lines().nth(1).unwrap().starts_with("S1080400"));
+    assert_eq!(text.lines().last(), Some("S9030400F8"));
+    assert_eq!(Format::Srec.read(&srec, 0), Ok((program.base, program.bytes())));
+    assert!(Format::Srec.read(b"S1040400FF00\n", 0).is_err());
+}
+
+#[test]
+fn hdl() {
+    use crate::format::{Format, Options};
+    let bytes = [0x12, 0x34, 0x56];
+    let text = |format : Format, options : Options| String::from_utf8(format.write(&bytes, 0, &options).unwrap()).unwrap();
+
+    assert_eq!(text(Format::Readmemh, Options::default()), "12\n34\n56\n");
+    let words = Options { word_width: 16, depth: Some(4), ..Default::default() };
+    assert_eq!(text(Format::Readmemh, words.clone()), "3412\n0056\n0000\n0000\n");
+    assert_eq!(text(Format::Readmemh, Options { big_endian: true, ..words.clone() }), "1234\n5600\n0000\n0000\n");
+    assert_eq!(text(Format::Readmemb, Options { depth: Some(3), ..Default::default() }), "00010010\n00110100\n01010110\n");
+    assert!(text(Format::Mif, words.clone()).contains("DEPTH = 4;\nWIDTH = 16;\n"));
+    assert!(text(Format::Mif, words.clone()).contains("    0001 : 0056;\n"));
+    assert_eq!(text(Format::Coe, Options::default()), "memory_initialization_radix=16;\nmemory_initialization_vector=\n12,\n34,\n56;\n");
+    assert_eq!(text(Format::Logisim, words), "v2.0 raw\n3412 56 0 0\n");
+    assert!(Format::Mif.write(&bytes, 0, &Options { depth: Some(2), ..Default::default() }).is_err());
+}
+
+#[test]
+fn embed() {
+    use crate::format::{Format, Options};
+    let program = assemble(".org 0x0400\nstart: nop\n.equ answer, 42").unwrap();
+    let options = Options { name: "boot-rom".to_string(), ..Default::default() };
+    let text = |format : Format| String::from_utf8(format.write_program(&program, &options).unwrap()).unwrap();
+    let len = program.bytes().len();
+
+    let c = text(Format::C);
+    assert!(c.contains("#define BOOT_ROM_BASE 0x0400u\n"));
+    assert!(c.contains("#define BOOT_ROM_ANSWER 0x002Au\n#define BOOT_ROM_START 0x0400u\n"));
+    assert!(c.contains(&format!("static const uint8_t boot_rom[{len}] = {{\n    0x")));
+
+    let rust = text(Format::Rust);
+    assert!(rust.contains("pub const BOOT_ROM_START: u16 = 0x0400;\n"));
+    assert!(rust.contains(&format!("pub const BOOT_ROM: [u8; {len}] = [\n")));
+
+    for code in ["foo: nop\nFOO: nop", "base: nop"] {
+        assert!(Format::C.write_program(&assemble(code).unwrap(), &options).is_err(), "{code}");
+    }
+
+    assert_eq!(crate::format::embed::hexdump(b"sasm\x00\x01", 0x10), "00000010: 7361 736d 0001                           sasm..\n");
+}
+
+#[test]
+fn elf() {
+    use crate::{debug::DebugInfo, format::elf};
+    let program = assemble(".org 0x0400\nstart: nop\njmp start, r5\n.section data\ncount: db 7\n.equ answer, 42").unwrap();
+    let image = elf::write_program(&program);
+    assert_eq!(&image[..4], b"\x7FELF");
+    assert_eq!(u16::from_le_bytes([image[18], image[19]]), elf::MACHINE);
+
+    let loaded = elf::read(&image).unwrap();
+    assert_eq!((loaded.entry, loaded.base, &loaded.bytes), (0x0400, 0x0400, &program.bytes()));
+    assert_eq!(loaded.debug, Some(DebugInfo::new(&program)));
+    let mut symbols = loaded.symbols.clone();
+    symbols.sort_by(|a, b| (a.address, a.kind, &a.name).cmp(&(b.address, b.kind, &b.name)));
+    assert_eq!(symbols, program.symbols);
+
+    assert_eq!(crate::format::Format::Elf.read(&image, 0), Ok((0x0400, program.bytes())));
+    assert!(elf::read(&crate::compile("nop").unwrap()).is_err());
+}
+
+#[test]
+fn split() {
+    use crate::format::split::{Split, report, split};
+    let chips = split(&[1, 2, 3, 4, 5], 0x0400, Split::EvenOdd, 4, 0xFF).unwrap();
+    assert_eq!(chips[0].bytes, vec![1, 3, 5, 0xFF]);
+    assert_eq!((chips[1].name.as_str(), &chips[1].bytes, chips[1].used), ("odd", &vec![2, 4, 0xFF, 0xFF], 2));
+    assert_eq!(report(&chips).lines().next(), Some("even      from 0x0400      3 /     4 bytes   75.0%"));
+
+    let banks = split(&[1, 2, 3, 4, 5], 0x0400, Split::Banks(2), 4, 0).unwrap();
+    assert_eq!(banks.iter().map(|chip| chip.bytes.clone()).collect::<Vec<_>>(), vec![vec![1, 2, 0, 0], vec![3, 4, 0, 0], vec![5, 0, 0, 0]]);
+
+    assert_eq!(banks.iter().map(|chip| chip.address).collect::<Vec<_>>(), vec![0x0400, 0x0402, 0x0404]);
+
+    let odd = split(&[1, 2, 3], 0x0401, Split::EvenOdd, 2, 0xFF).unwrap();
+    assert_eq!((odd[0].address, &odd[0].bytes, odd[0].used), (0x0400, &vec![0xFF, 2], 1));
+    assert_eq!((odd[1].address, &odd[1].bytes, odd[1].used), (0x0401, &vec![1, 3], 2));
+
+    assert!(split(&[0; 9], 0, Split::EvenOdd, 4, 0).is_err());
+    assert!(split(&[0; 9], 0, Split::Banks(8), 4, 0).is_err());
+}
+
+fn vm(code : &str) -> crate::vm::Vm {
+    crate::vm::Vm::from_program(&assemble(code).unwrap())
+}
+
+#[test]
+fn vm_run() {
+    use crate::vm::Stop;
+    let mut machine = vm("mov 5, r0\nadd 3, r0\npush r0\npop r1\nmov 0x1234, r2\nmov 0xFF, rb2\ncmp 9, r1\njlt done, r5\nnot r0\ndone: ret");
+    assert_eq!(machine.run(Some(100)), Ok(Stop::Returned));
+    assert_eq!(machine.reg(Register::r0()).unwrap(), 8);
+    assert_eq!(machine.reg(Register::r1()).unwrap(), 8);
+    assert_eq!(machine.reg(Register::r2()).unwrap(), 0x12FF);
+    assert_eq!(machine.sp, crate::vm::STACK_TOP);
+
+    assert_eq!(vm("mov -2, r5\njmp r5").run(Some(100)), Ok(Stop::Halted));
+    assert_eq!(vm("loop: jmp loop, r5").run(Some(100)), Ok(Stop::CycleLimit));
+    assert!(matches!(vm("db 0xFF, 0xFF").run(Some(100)), Err(Error::Fault(0, _))));
+}
+
+#[test]
+fn vm_runtime() {
+    let mut machine = vm("mov 123, r2\nmov 45, r3\nmul r2, r3\nmov 1234, r4\ndiv 56, r4\nmov 1234, r6\nmod 56, r6\nmov 200, rb7\nmov 7, rb8\ndiv rb8, rb7\nret");
+    machine.run(Some(100_000)).unwrap();
+    assert_eq!(machine.registers[3], 123 * 45);
+    assert_eq!(machine.registers[4], 1234 / 56);
+    assert_eq!(machine.registers[6], 1234 % 56);
+    assert_eq!(machine.registers[7] & 0xFF, 200 / 7);
+}
+
+#[test]
+fn runtime_values() {
+    for (op, lhs, rhs, result, r1) in [("mul", 123, 45, 123 * 45, 0), ("mul", 0xFFFF, 2, 0xFFFE, 0), ("div", 1000, 7, 142, 6), ("div", 5, 0, 0xFFFF, 5)] {
+        let mut machine = vm(&format!("mov {lhs}, r0\nmov {rhs}, r1\ncall sasm_{op}\nret"));
+        machine.run(Some(100_000)).unwrap();
+        assert_eq!(machine.registers[0], result, "{op} {lhs}, {rhs}");
+        if op == "div" {
+            assert_eq!(machine.registers[1], r1, "{op} {lhs}, {rhs}");
+        }
+    }
+
+    // The byte routines leave the high halves alone
+    let mut machine = vm("mov 0x1214, r0\nmov 0x3403, r1\ncall sasm_divb\nret");
+    machine.run(Some(100_000)).unwrap();
+    assert_eq!((machine.registers[0], machine.registers[1]), (0x1206, 0x3402));
+}
+
+#[test]
+fn vm_stdlib() {
+    let mut machine = vm("mov 12345, r0\nmov 0x8000, r1\ncall utoa\nmov r0, r7\nmov 0x8000, r0\ncall strlen\nret\n.include <std/fmt.sasm>\n.include <std/string.sasm>");
+    machine.run(Some(100_000)).unwrap();
+    assert_eq!(&machine.memory[0x8000..0x8006], b"12345\0");
+    assert_eq!((machine.registers[7], machine.registers[0]), (5, 5));
+}
+
+#[test]
+fn vm_devices() {
+    use std::sync::{Arc, Mutex};
+    use crate::vm::{Stop, device::{Console, Exit, Input}};
+
+    let mut machine = vm("\
+        mov 0xFF10, r1\nmov 0xFF00, r2\nmov 0xFF20, r3\n\
+        loop:\nmov [r1], rb0\ncmp 0, rb0\njeq loop, r5\ncmp 2, rb0\njeq done, r5\n\
+        add 1, r1\nmov [r1], rb0\nsub 1, r1\nmov rb0, [r2]\njmp loop, r5\n\
+        done:\nmov 3, rb0\nmov rb0, [r3]\nloop2: jmp loop2, r5");
+    let output = Arc::new(Mutex::new(Vec::new()));
+    machine.attach(0xFF00, Box::new(Console::buffered(output.clone()))).unwrap();
+    machine.attach(0xFF10, Box::new(Input::from_bytes(b"hi"))).unwrap();
+    machine.attach(0xFF20, Box::new(Exit::default())).unwrap();
+    assert!(machine.attach(0xFF11, Box::new(Exit::default())).is_err());
+
+    assert_eq!(machine.run(Some(1000)), Ok(Stop::Exit(3)));
+    assert_eq!(*output.lock().unwrap(), b"hi");
+    assert_eq!(crate::vm::device::parse_mapping("console@0xFF00"), Ok(("console".to_string(), 0xFF00)));
+    assert!(crate::vm::device::DEFAULT_MAP.iter().all(|(_, address)| *address >= crate::vm::STACK_TOP));
+}
+
+#[test]
+fn vm_interrupts() {
+    use crate::vm::device::Timer;
+
+    let mut machine = vm("\
+        mov 0x8000, r1\nmov tick, r0\nmov r0, [r1]\n\
+        mov 0xFF04, r2\nmov 10, rb0\nmov rb0, [r2]\n\
+        mov 0, r3\nsti r1\n\
+        wait:\ncmp 5, r3\njlt wait, r5\ncli\nret\n\
+        tick:\nadd 1, r3\nret");
+    machine.attach(0xFF04, Box::new(Timer::default())).unwrap();
+    machine.run(Some(1000)).unwrap();
+    assert_eq!(machine.registers[3], 5);
+    assert!(machine.cycles > 50 && !machine.interrupts);
+
+    let mut machine = vm("\
+        mov 0x8000, r1\nmov handler, r0\nmov r0, [r1]\nadd 2, r1\nmov r0, [r1]\n\
+        sub 2, r1\nsti r1\ncli\nmov 1, r4\nint r4\nret\n\
+        handler:\nmov 42, r6\nret");
+    machine.run(Some(1000)).unwrap();
+    assert_eq!((machine.registers[6], machine.interrupts), (42, false));
+    assert!(matches!(vm("mov 1, r4\nint r4").run(Some(10)), Err(Error::Fault(_, _))));
+
+    // A timer going off under cli only ever has one request waiting
+    let mut machine = vm("mov 0xFF04, r2\nmov 1, rb0\nmov rb0, [r2]\ncli\nloop: jmp loop, r5");
+    machine.attach(0xFF04, Box::new(Timer::default())).unwrap();
+    machine.history.capacity = 100;
+    assert_eq!(machine.run(Some(1000)), Ok(crate::vm::Stop::CycleLimit));
+    assert_eq!(machine.pending.len(), 1);
+}
+
+#[test]
+fn vm_history() {
+    let code = "mov 0x8000, r1\nmov 7, rb0\nmov rb0, [r1]\npush r1\ncall f\npop r1\nret\nf: add 1, r0\nret";
+    let mut machine = vm(code);
+    machine.history.capacity = 3;
+    machine.run(Some(100)).unwrap();
+    assert_eq!((machine.history.len(), machine.registers[0]), (3, 8));
+
+    let mut machine = vm(code);
+    let start = (machine.register_file(), machine.memory.clone());
+    machine.history.capacity = 100;
+    machine.run(Some(100)).unwrap();
+    assert_eq!(machine.memory[0x8000], 7);
+    while machine.undo().is_some() {}
+    assert_eq!((machine.register_file(), machine.memory.clone(), machine.cycles), (start.0, start.1, 0));
+}
+
+#[test]
+fn record_replay() {
+    use std::sync::{Arc, Mutex};
+    use crate::vm::{Stop, device::{Console, Exit, Input}, record};
+
+    let code = "\
+        mov 0xFF10, r1\nmov 0xFF00, r2\nmov 0xFF20, r3\n\
+        loop:\nmov [r1], rb0\ncmp 0, rb0\njeq loop, r5\ncmp 2, rb0\njeq done, r5\n\
+        add 1, r1\nmov [r1], rb0\nsub 1, r1\nmov rb0, [r2]\njmp loop, r5\n\
+        done:\nmov 3, rb0\nmov rb0, [r3]\nloop2: jmp loop2, r5";
+    let machine = |input : &[u8], output : &Arc<Mutex<Vec<u8>>>| {
+        let mut machine = vm(code);
+        machine.attach(0xFF00, Box::new(Console::buffered(output.clone()))).unwrap();
+        machine.attach(0xFF10, Box::new(Input::from_bytes(input))).unwrap();
+        machine.attach(0xFF20, Box::new(Exit::default())).unwrap();
+        machine
+    };
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let mut recording = Vec::new();
+    assert_eq!(record::record(&mut machine(b"hi", &output), Some(1000), &mut recording), Ok(Stop::Exit(3)));
+    let recording = String::from_utf8(recording).unwrap();
+    assert!(recording.starts_with("sasm recording\n@0000 r1=FF10 pc="));
+    assert!(recording.contains(" iFF10=01\n") && recording.contains(" iFF11=68\n"));
+
+    let replayed = Arc::new(Mutex::new(Vec::new()));
+    let mut replay = machine(b"", &replayed);
+    replay.replay(record::read(&recording).unwrap());
+    assert_eq!(replay.run(Some(1000)), Ok(Stop::Exit(3)));
+    assert_eq!(*replayed.lock().unwrap(), b"hi");
+
+    let mut replay = machine(b"", &Arc::default());
+    replay.replay(record::read(&recording.replacen("iFF11=68", "iFF11=6A", 1)).unwrap());
+    assert!(matches!(replay.run(Some(1000)), Err(Error::External(err)) if err.starts_with("replay diverged")));
+    assert!(record::read("@0000").is_err());
+}
+
+#[test]
+fn trace() {
+    use crate::{json::Json, vm::trace::{Format, Tracer, Vcd}};
+
+    let mut machine = vm("mov 0x8000, r1\nmov 7, rb0\nmov rb0, [r1]\ncmp 7, rb0\nret");
+    let (mut text, mut json, mut vcd) = (Vec::new(), Vec::new(), Vec::new());
+    {
+        let mut tracer = Tracer::new(&mut text, Format::Text, &machine);
+        let mut json_tracer = Tracer::new(&mut json, Format::Json, &machine);
+        let mut vcd = Vcd::new(&mut vcd, &machine).unwrap();
+        machine.run_observed(Some(100), &mut [&mut tracer, &mut json_tracer, &mut vcd]).unwrap();
+    }
+
+    let text = String::from_utf8(text).unwrap();
+    let lines : Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("       0  0000  mov") && lines[0].ends_with(" r1=0x8000"));
+    assert!(lines[2].ends_with(" [0x8000]=0x07"));
+    assert!(lines[3].ends_with(" flags=Z----"));
+
+    let json : Vec<Json> = String::from_utf8(json).unwrap().lines().map(|line| Json::parse(line).unwrap()).collect();
+    assert_eq!(json[0].get("registers").unwrap().to_string(), r#"{"r1":32768}"#);
+    assert_eq!(json[2].get("writes").unwrap().to_string(), r#"[{"address":32768,"value":7}]"#);
+    assert_eq!(json[4].get("cycle").and_then(Json::as_i64), Some(4));
+
+    let vcd = String::from_utf8(vcd).unwrap();
+    assert!(vcd.starts_with("$version sasm $end\n$timescale 1ns $end\n"));
+    assert!(vcd.contains("$var reg 16 ! r0 $end\n") && vcd.contains("$var reg 1 3 zero $end\n"));
+    assert!(vcd.contains("#1\nb1000000000000000 \"\n") && vcd.contains("\n13\n"));
+}
+
+#[test]
+fn coverage() {
+    use crate::{coverage::Coverage, debug::DebugInfo};
+
+    let program = assemble("start: mov 1, r0\ncall double\ncall double\nret\nunused: mov 5, r0\nret\ndouble: add r0, r0\nret").unwrap();
+    let info = DebugInfo::new(&program);
+    let mut machine = crate::vm::Vm::from_program(&program);
+    let mut coverage = Coverage::new();
+    machine.run_observed(Some(100), &mut [&mut coverage]).unwrap();
+
+    let lines = coverage.lines(&info);
+    assert_eq!(lines.values().copied().collect::<Vec<_>>(), [1, 1, 1, 1, 0, 0, 2, 2]);
+    assert_eq!(coverage.labels(&info), [("start", 1), ("unused", 0), ("double", 2)]);
+    assert_eq!(coverage.routines.values().copied().collect::<Vec<_>>(), [(2, 4)]);
+
+    let report = coverage.report(&program, &info);
+    assert!(report.starts_with("; <input>\n        1      1  start: mov 1, r0\n"));
+    assert!(report.contains("    #####      5  unused: mov 5, r0\n") && report.contains("    #####  unused\n"));
+    assert!(report.ends_with("6 of 8 lines executed (75.0%)\n"));
+
+    let lcov = coverage.lcov(&info);
+    assert!(lcov.starts_with("TN:\nSF:<input>\nFN:1,start\n"));
+    assert!(lcov.contains("FNDA:2,double\nFNF:3\nFNH:2\n") && lcov.contains("DA:5,0\n") && lcov.ends_with("LF:8\nLH:6\nend_of_record\n"));
+
+    let profile = coverage.profile(&info);
+    let rows : Vec<&str> = profile.lines().collect();
+    assert_eq!(rows.len(), 4);
+    assert!(rows[1].contains("50.0%") && rows[1].ends_with("2  double") && rows[2].ends_with("start"));
+    assert_eq!(rows[3].trim(), "8  instructions");
+}
+
+#[test]
+fn testing() {
+    use crate::{compile, source, testing};
+
+    let code = "start: mov 1, r0\nret\ndouble: add r0, r0\nret\n";
+    let tests = ".test \"doubles\"\nmov 3, r0\ncall double\n.expect r0 == 6\n.expect r0 < 6\n.endtest\n\
+        .test \"spins\"\nspin: jmp spin, r5\n.endtest\n";
+    assert_eq!(compile(&format!("{code}{tests}")), compile(code));
+    assert!(matches!(compile(".test \"open\"\nnop\n"), Err(Error::Directive(_))));
+
+    let outcomes = testing::run_tests(&source::load("<input>", None, &format!("{code}{tests}")).unwrap(), 100).unwrap();
+    assert_eq!(outcomes.iter().map(|outcome| (outcome.name.as_str(), outcome.line)).collect::<Vec<_>>(), [("doubles", 5), ("spins", 11)]);
+    assert_eq!(outcomes[0].failures, ["<input>:9: .expect r0 < 6 failed, it was 0x0006"]);
+    assert_eq!(outcomes[1].failures, ["still running after 100 cycles"]);
+
+    let report = testing::report(&outcomes);
+    assert!(report.starts_with("running 2 tests\ntest doubles ... FAILED\n"));
+    assert!(report.ends_with("test result: FAILED. 0 passed; 2 failed\n"));
+}
+
+#[test]
+fn machine() {
+    use crate::machine::Machine;
+
+    let mut machine = Machine::from_source("fib: mov 0, r1\nmov 1, r2\nloop: cmp 0, r0\njeq done, r5\n\
+        mov r2, r3\nadd r1, r3\nmov r2, r1\nmov r3, r2\nsub 1, r0\njmp loop, r5\ndone: mov r1, r0\nret\n\
+        spin: jmp spin, r5\nbad: db 0xFF, 0xFF").unwrap();
+    assert_eq!(machine.set_reg(Register::r0(), 10).unwrap().call("fib").unwrap().reg(Register::r0()).unwrap(), 55);
+    assert_eq!(machine.set_reg(Register::r0(), 1).unwrap().call("fib").unwrap().reg(Register::r0()).unwrap(), 1);
+
+    let err = machine.budget(100).call("spin").unwrap_err().to_string();
+    assert!(err.starts_with("spin didn't return: still running after 100 instructions, at 0x") && err.contains("(spin"));
+    let err = machine.call("bad").unwrap_err().to_string();
+    assert!(err.starts_with("bad didn't return: fault at") && err.ends_with("(bad)"));
+    assert_eq!(machine.call("nope").unwrap_err(), Error::NoSuchIdentifier("nope".to_string()));
+}
+
+fn debugger(code : &str) -> crate::debugger::Debugger {
+    let program = assemble(code).unwrap();
+    let mut vm = crate::vm::Vm::from_program(&program);
+    vm.history.capacity = crate::debugger::HISTORY;
+    crate::debugger::Debugger::new(program, vm)
+}
+
+const DEBUG_CODE : &str = "start: mov 1, r0\ncall double\ncall double\nmov r0, r1\nret\ndouble: add r0, r0\nret";
+
+#[test]
+fn debugger_steps() {
+    use crate::{debugger::Event, vm::Stop};
+    let mut dbg = debugger(DEBUG_CODE);
+    let double = dbg.resolve("double").unwrap()[0];
+    let line3 = dbg.resolve("<input>:3").unwrap()[0];
+
+    dbg.breakpoints.insert(double);
+    assert_eq!(dbg.cont(), Ok(Event::Breakpoint(double)));
+    assert_eq!(dbg.finish(), Ok(Event::Step));
+    assert_eq!(dbg.vm.pc, line3);
+
+    dbg.breakpoints.clear();
+    assert_eq!(dbg.next(), Ok(Event::Step));
+    assert_eq!(dbg.vm.registers[0], 4);
+    assert_eq!(dbg.location(dbg.vm.pc).as_deref(), Some("<input>:4  mov r0, r1"));
+    assert_eq!(dbg.cont(), Ok(Event::Stopped(Stop::Returned)));
+
+    let mut dbg = debugger("mov 0x8000, r1\nmov 7, rb0\nmov rb0, [r1]\nret");
+    dbg.command("watch 0x8000").unwrap();
+    assert_eq!(dbg.cont(), Ok(Event::Watchpoint(0x8000, 0, 7)));
+}
+
+#[test]
+fn debugger_reverse() {
+    use crate::debugger::Event;
+    let mut dbg = debugger(DEBUG_CODE);
+    let double = dbg.resolve("double").unwrap()[0];
+    let calls = dbg.resolve("<input>:2").unwrap()[0];
+
+    dbg.breakpoints.insert(double);
+    assert_eq!(dbg.cont(), Ok(Event::Breakpoint(double)));
+    assert_eq!(dbg.frames.len(), 1);
+    assert_eq!(dbg.reverse_step(), Ok(Event::Step));
+    assert_eq!((dbg.vm.pc, dbg.frames.len()), (calls, 0));
+    assert_eq!(dbg.reverse_cont(), Ok(Event::HistoryStart));
+    assert_eq!((dbg.vm.pc, dbg.vm.registers[0]), (0, 0));
+
+    assert_eq!(dbg.cont(), Ok(Event::Breakpoint(double)));
+    assert_eq!(dbg.cont(), Ok(Event::Breakpoint(double)));
+    assert_eq!(dbg.vm.registers[0], 2);
+    assert!(dbg.command("reverse-continue").unwrap().unwrap().starts_with(&format!("breakpoint at {double:#06X}\n<input>:6  double")));
+    assert_eq!((dbg.vm.registers[0], dbg.frames.len()), (1, 1));
+    assert_eq!(dbg.frames[0].call, calls);
+}
+
+#[test]
+fn debugger_repl() {
+    let mut output = Vec::new();
+    debugger(DEBUG_CODE).repl("break double\ncontinue\nbogus\nquit\nstep\n".as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("<input>:1  start: mov 1, r0\n=> 0x0000  mov 0x01, r0\n(sasm) breakpoint at"));
+    assert!(output.contains("<input>:6  double: add r0, r0\n"));
+    assert!(output.contains("error: unknown command bogus"));
+    assert!(output.ends_with("(sasm) "));
+}
+
+#[test]
+fn gdb_session() {
+    let packet = |data : &str| format!("${data}#{:02x}", data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte)));
+    let double = debugger(DEBUG_CODE).resolve("double").unwrap()[0];
+    let input : String = ["qSupported:xmlRegisters=i386", "qXfer:features:read:target.xml:0,8000", "g", "P0=3412",
+        &format!("Z0,{double:x},1"), "c", "p0", "m0,2", "M8000,2:abcd", "m8000,2", "c",
+        // #, $ and } escaped
+        "X8000,3:}\u{3}}\u{4}}]", "m8000,3", "D"]
+        .iter().map(|data| packet(data)).collect();
+
+    let mut output = Vec::new();
+    let mut dbg = debugger(DEBUG_CODE);
+    crate::gdb::session(&mut dbg, input.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    let replies : Vec<&str> = output.split('$').skip(1).map(|reply| reply.split('#').next().unwrap()).collect();
+    assert_eq!(replies[0], "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+");
+    assert!(replies[1].starts_with("l<?xml") && replies[1].contains("<reg name=\"pc\" bitsize=\"16\""));
+    assert!(replies[1].contains("<architecture>smplcore</architecture>"));
+    assert_eq!(replies[2].len(), 19 * 4);
+    assert_eq!(&replies[3..6], ["OK", "OK", "S05"]);
+    assert_eq!(replies[6], "0100");
+    assert_eq!(&replies[8..], ["OK", "abcd", "S05", "OK", "23247d", "OK"]);
+    assert_eq!(dbg.vm.pc, double);
+    assert!(output.starts_with('+'));
+}
+
+#[test]
+fn json() {
+    use crate::json::Json;
+    let text = r#"{"a":[1,-2.5,true,null],"b":"q\"\n\u00e9","c":{}}"#;
+    let json = Json::parse(text).unwrap();
+    assert_eq!(json.get("a").and_then(Json::as_array).map(|a| a.len()), Some(4));
+    assert_eq!(json.get("b").and_then(Json::as_str), Some("q\"\né"));
+    assert_eq!(json.to_string(), text.replace("\\u00e9", "é"));
+    assert!(Json::parse("{\"a\":}").is_err());
+    assert_eq!(Json::parse(r#""\ud83d\ude00 \ud83d""#).unwrap().as_str(), Some("\u{1F600} \u{FFFD}"));
+}
+
+#[test]
+fn dap_session() {
+    use crate::json::Json;
+    let path = std::env::temp_dir().join("sasm_dap_session.sasm");
+    std::fs::write(&path, DEBUG_CODE).unwrap();
+    let path = path.to_string_lossy().to_string();
+
+    let requests = [
+        r#"{"command":"initialize","arguments":{}}"#.to_string(),
+        format!(r#"{{"command":"launch","arguments":{{"program":{},"stopOnEntry":true}}}}"#, Json::from(path.as_str())),
+        format!(r#"{{"command":"setBreakpoints","arguments":{{"source":{{"path":{}}},"breakpoints":[{{"line":6}},{{"line":99}}]}}}}"#, Json::from(path.as_str())),
+        r#"{"command":"configurationDone"}"#.to_string(),
+        r#"{"command":"continue","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"command":"stackTrace","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"command":"evaluate","arguments":{"expression":"r0"}}"#.to_string(),
+        r#"{"command":"evaluate","arguments":{"expression":"double"}}"#.to_string(),
+        format!(r#"{{"command":"setBreakpoints","arguments":{{"source":{{"path":{}}},"breakpoints":[]}}}}"#, Json::from(path.as_str())),
+        r#"{"command":"continue","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"command":"pause","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"command":"disconnect"}"#.to_string(),
+    ];
+    let input : String = requests.iter().enumerate()
+        .map(|(seq, request)| {
+            let request = request.replacen('{', &format!("{{\"seq\":{},\"type\":\"request\",", seq + 1), 1);
+            format!("Content-Length: {}\r\n\r\n{request}", request.len())
+        })
+        .collect();
+
+    let mut output = Vec::new();
+    crate::dap::serve(input.as_bytes(), &mut output).unwrap();
+    let mut reader = &output[..];
+    let mut messages = Vec::new();
+    while let Some(message) = crate::dap::read_message(&mut reader).unwrap() {
+        messages.push(message);
+    }
+
+    let find = |command : &str| messages.iter().filter(move |m| m.get("command").and_then(Json::as_str) == Some(command));
+    let events : Vec<&str> = messages.iter().filter_map(|m| m.get("event").and_then(Json::as_str)).collect();
+    assert_eq!(events, ["initialized", "stopped", "stopped", "exited", "terminated"]);
+
+    assert_eq!(find("pause").next().unwrap().get("success").and_then(Json::as_bool), Some(false));
+    let breakpoints = find("setBreakpoints").next().unwrap().get("body").unwrap().get("breakpoints").unwrap().to_string();
+    assert_eq!(breakpoints, r#"[{"verified":true,"line":6},{"verified":false,"line":99}]"#);
+    let frames = find("stackTrace").next().unwrap().get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap().to_vec();
+    assert_eq!(frames.iter().map(|f| f.get("name").unwrap().as_str().unwrap()).collect::<Vec<_>>(), ["double", "start"]);
+    assert_eq!(frames[1].get("line").and_then(Json::as_i64), Some(2));
+    let results : Vec<String> = find("evaluate").map(|m| m.get("body").unwrap().get("result").unwrap().to_string()).collect();
+    assert_eq!(results[0], "\"0x0001\"");
+    assert!(results[1].starts_with("\"0x00"));
+    assert!(find("continue").all(|m| m.get("success") == Some(&Json::Bool(true))));
+}
+
+#[test]
+fn build() {
+    use crate::build::Assembler;
+
+    let dir = std::env::temp_dir().join(format!("sasm_build_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("main.sasm"), "start: mov 1, r0\ncall double\nret\n.include \"double.sasm\"\n").unwrap();
+    std::fs::write(dir.join("lib/double.sasm"), "double: add r0, r0\nret\n").unwrap();
+    std::fs::write(dir.join("bad.sasm"), "start: mov 1, r0\n  jmp nowhere, r5\n").unwrap();
+
+    let program = Assembler::new().file(dir.join("main.sasm")).include_dir(dir.join("lib")).out_dir(&dir).try_compile("fw").unwrap();
+    let bytes = crate::compile("start: mov 1, r0\ncall double\nret\ndouble: add r0, r0\nret").unwrap();
+    assert_eq!(program.bytes(), bytes);
+    assert_eq!(std::fs::read(dir.join("fw.bin")).unwrap(), bytes);
+    assert!(std::fs::read_to_string(dir.join("fw.map")).unwrap().contains("double"));
+
+    let Err(Error::External(err)) = Assembler::new().file(dir.join("bad.sasm")).out_dir(&dir).try_compile("bad") else { panic!() };
+    let path = dir.join("bad.sasm").display().to_string();
+    assert_eq!(err, format!("error: identifier nowhere not defined\n --> {path}:2:7\n  |\n2 |   jmp nowhere, r5\n  |       ^^^^^^^"));
+    assert!(Assembler::new().file(dir.join("a\"b.sasm")).out_dir(&dir).try_compile("quoted").is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
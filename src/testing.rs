@@ -0,0 +1,296 @@
+//! Unit tests written in sasm itself.
+//!
+//! A test is a block of code between `.test "name"` and `.endtest`, usually setting up registers
+//! and memory and calling the routine under test, with `.expect` assertions along the way:
+//!
+//! ```text
+//! .test "doubles"
+//!     mov 3, r0
+//!     call double
+//!     .expect r0 == 6
+//!     .expect [buf] == 0x41
+//! .endtest
+//! ```
+//!
+//! An assertion compares a register, a byte of memory `[address]` or a word `word [address]` with
+//! `==`, `!=`, `<`, `>`, `<=` or `>=` against a number or symbol, and is checked every time
+//! execution reaches it. Normal builds leave test blocks out. Each test is built on its own, with
+//! its block after the rest of the program, and runs until it falls off the end of the block.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use crate::{Program, parser::{assemble_lines, parse_number}, source::{self, Line}, utils::{self, Error, Result}, vm::{Stop, Vm, device}};
+
+const ENTRY : &str = "sasm_test_entry";
+const END : &str = "sasm_test_end";
+
+/// Where test blocks start and end, as indices of their `.test` and `.endtest` lines
+fn blocks(lines : &[Line]) -> Result<Vec<(usize, usize)>> {
+    let mut res = Vec::new();
+    let mut start = None;
+    for (i, line) in lines.iter().enumerate() {
+        match (line.code.split_whitespace().next(), start) {
+            (Some(".test"), None) => start = Some(i),
+            (Some(".endtest"), Some(first)) => {
+                res.push((first, i));
+                start = None;
+            },
+            (Some(".test"), Some(_)) => return Err(Error::Directive(format!("{}:{}: .test inside another test", line.file, line.number))),
+            (Some(".endtest"), None) => return Err(Error::Directive(format!("{}:{}: .endtest outside a test", line.file, line.number))),
+            _ => (),
+        }
+    }
+    match start {
+        Some(first) => Err(Error::Directive(format!("{}:{}: .test without .endtest", lines[first].file, lines[first].number))),
+        None => Ok(res),
+    }
+}
+
+/// Blanks out test blocks, so they don't take up any room in the program
+pub(crate) fn strip(lines : &mut [Line]) -> Result<()> {
+    for (start, end) in blocks(lines)? {
+        for line in lines[start..=end].iter_mut() {
+            line.code.clear();
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Place {
+    Register(usize, bool),
+    Sp,
+    Byte(String),
+    Word(String),
+}
+
+/// A `.expect` assertion
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expect {
+    file : String,
+    line : usize,
+    text : String,
+    place : Place,
+    compare : Compare,
+    value : String,
+}
+
+impl Expect {
+    fn parse(line : &Line) -> Result<Self> {
+        let text = line.code.trim().to_string();
+        let malformed = || Error::Directive(text.clone());
+        let args = text.strip_prefix(".expect").ok_or_else(malformed)?.trim();
+
+        let (split, compare) = ["==", "!=", "<=", ">=", "<", ">"].iter()
+            .find_map(|op| args.find(op).map(|at| (at, *op)))
+            .ok_or_else(malformed)?;
+        let (place, value) = (args[..split].trim(), args[split + compare.len()..].trim());
+        let compare = match compare {
+            "==" => Compare::Eq,
+            "!=" => Compare::Ne,
+            "<=" => Compare::Le,
+            ">=" => Compare::Ge,
+            "<" => Compare::Lt,
+            _ => Compare::Gt,
+        };
+
+        let address = |place : &str| place.strip_prefix('[').and_then(|place| place.strip_suffix(']')).map(|address| address.trim().to_string());
+        let place = if place == "sp" {
+            Place::Sp
+        } else if let Some((_, reg)) = utils::registers().iter().find(|(name, _)| name == place) {
+            let (idx, byte) = utils::register_index(*reg)?;
+            Place::Register(idx, byte)
+        } else if let Some(address) = place.strip_prefix("word").and_then(|place| address(place.trim())) {
+            Place::Word(address)
+        } else if let Some(address) = address(place) {
+            Place::Byte(address)
+        } else {
+            return Err(malformed())
+        };
+
+        if value.is_empty() {
+            return Err(malformed())
+        }
+        Ok(Self { file: line.file.clone(), line: line.number, text: text.clone(), place, compare, value: value.to_string() })
+    }
+
+    /// A failure message, unless the assertion holds
+    fn check(&self, vm : &Vm, identifiers : &HashMap<String, u16>) -> Option<String> {
+        let resolve = |text : &str| parse_number(text).map(|value| value as u16).or_else(|| identifiers.get(text).copied());
+        let location = format!("{}:{}", self.file, self.line);
+        let Some(value) = resolve(&self.value) else {
+            return Some(format!("{location}: {} isn't a number or symbol", self.value))
+        };
+
+        let word = |address : u16| u16::from_le_bytes([vm.memory[address as usize], vm.memory[address.wrapping_add(1) as usize]]);
+        let actual = match &self.place {
+            Place::Register(idx, true) => vm.registers[*idx] & 0xFF,
+            Place::Register(idx, false) => vm.registers[*idx],
+            Place::Sp => vm.sp,
+            Place::Byte(address) | Place::Word(address) => match resolve(address) {
+                Some(address) if matches!(self.place, Place::Byte(_)) => vm.memory[address as usize] as u16,
+                Some(address) => word(address),
+                None => return Some(format!("{location}: {address} isn't a number or symbol")),
+            },
+        };
+
+        let holds = match self.compare {
+            Compare::Eq => actual == value,
+            Compare::Ne => actual != value,
+            Compare::Lt => actual < value,
+            Compare::Gt => actual > value,
+            Compare::Le => actual <= value,
+            Compare::Ge => actual >= value,
+        };
+        (!holds).then(|| format!("{location}: {} failed, it was {actual:#06X}", self.text))
+    }
+}
+
+/// How a test went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub name : String,
+    pub file : String,
+    pub line : usize,
+    /// Why it failed, empty if it passed
+    pub failures : Vec<String>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// The program for the test between `lines[start]` and `lines[end]`, with the assertions in it
+fn build(lines : &[Line], start : usize, end : usize) -> Result<(Program, Vec<(String, Expect)>)> {
+    let mut res = lines.to_vec();
+    strip(&mut res)?;
+
+    let label = |line : &Line, name : &str| Line { code: format!("{name}:"), ..line.clone() };
+    res.push(label(&lines[start], ENTRY));
+    let mut expects = Vec::new();
+    for line in lines[start + 1..end].iter() {
+        if line.code.split_whitespace().next() == Some(".expect") {
+            let name = format!("sasm_test_expect_{}", expects.len());
+            expects.push((name.clone(), Expect::parse(line)?));
+            res.push(label(line, &name));
+        } else {
+            res.push(line.clone());
+        }
+    }
+    res.push(label(&lines[end], END));
+    Ok((assemble_lines(res)?, expects))
+}
+
+/// Runs a single test, built from `program`
+fn run(program : &Program, expects : &[(String, Expect)], max_cycles : u64) -> Vec<String> {
+    let address = |name : &str| program.identifiers.get(name).copied().unwrap_or_default();
+    let (entry, end) = (address(ENTRY), address(END));
+    let expects : Vec<(u16, &Expect)> = expects.iter().map(|(name, expect)| (address(name), expect)).collect();
+    let mut reached = vec![false; expects.len()];
+
+    let mut vm = Vm::from_program(program);
+    vm.pc = entry;
+    if let Err(err) = device::attach_default(&mut vm, Arc::new(Mutex::new(Vec::new()))) {
+        return vec![err.to_string()]
+    }
+
+    let mut failures = Vec::new();
+    loop {
+        for (i, (address, expect)) in expects.iter().enumerate() {
+            if *address == vm.pc {
+                reached[i] = true;
+                failures.extend(expect.check(&vm, &program.identifiers));
+            }
+        }
+        if vm.pc == end {
+            break
+        }
+        if vm.cycles >= max_cycles {
+            failures.push(format!("still running after {max_cycles} cycles"));
+            break
+        }
+        match vm.step() {
+            Ok(None) => (),
+            Ok(Some(stop)) => {
+                let stop = match stop {
+                    Stop::Exit(code) => format!("exited with {code}"),
+                    Stop::Halted => "halted".to_string(),
+                    Stop::Returned => "returned".to_string(),
+                    Stop::CycleLimit => "ran out of cycles".to_string(),
+                };
+                failures.push(format!("{stop} before the end of the test"));
+                break
+            },
+            Err(err) => {
+                failures.push(err.to_string());
+                break
+            },
+        }
+    }
+
+    for ((_, expect), reached) in expects.iter().zip(reached) {
+        if !reached && failures.is_empty() {
+            failures.push(format!("{}:{}: {} never reached", expect.file, expect.line, expect.text));
+        }
+    }
+    failures
+}
+
+/// Builds and runs every test in `lines`, giving each up after `max_cycles` instructions
+pub fn run_tests(lines : &[Line], max_cycles : u64) -> Result<Vec<Outcome>> {
+    let mut res = Vec::new();
+    for (start, end) in blocks(lines)? {
+        let line = &lines[start];
+        let name = line.code.trim().strip_prefix(".test").map(str::trim)
+            .and_then(|name| name.strip_prefix('"')?.strip_suffix('"'))
+            .ok_or_else(|| Error::Directive(line.code.trim().to_string()))?;
+
+        let failures = match build(lines, start, end) {
+            Ok((program, expects)) => run(&program, &expects, max_cycles),
+            Err(err) => vec![err.to_string()],
+        };
+        res.push(Outcome { name: name.to_string(), file: line.file.clone(), line: line.number, failures });
+    }
+    Ok(res)
+}
+
+/// Builds and runs every test in the file at `fpath`
+pub fn run_file(fpath : &str, max_cycles : u64) -> Result<Vec<Outcome>> {
+    let code = std::fs::read_to_string(fpath).map_err(|err| Error::External(err.to_string()))?;
+    run_tests(&source::load(fpath, std::path::Path::new(fpath).parent(), &code)?, max_cycles)
+}
+
+/// A report of how the tests went, in the style of `cargo test`
+pub fn report(outcomes : &[Outcome]) -> String {
+    let mut res = format!("running {} tests\n", outcomes.len());
+    for outcome in outcomes.iter() {
+        res += &format!("test {} ... {}\n", outcome.name, if outcome.passed() { "ok" } else { "FAILED" });
+    }
+
+    let failed : Vec<&Outcome> = outcomes.iter().filter(|outcome| !outcome.passed()).collect();
+    if !failed.is_empty() {
+        res += "\nfailures:\n";
+        for outcome in failed.iter() {
+            res += &format!("\n---- {} ({}:{}) ----\n", outcome.name, outcome.file, outcome.line);
+            for failure in outcome.failures.iter() {
+                res += &format!("{failure}\n");
+            }
+        }
+    }
+
+    let result = if failed.is_empty() { "ok" } else { "FAILED" };
+    res += &format!("\ntest result: {result}. {} passed; {} failed\n", outcomes.len() - failed.len(), failed.len());
+    res
+}
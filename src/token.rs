@@ -34,6 +34,9 @@ pub enum Token {
     Shr,
     Shre,
     Cmp,
+    Mul,
+    Div,
+    Mod,
 
     AJmp,
     Jmp,
@@ -57,6 +60,52 @@ impl Token {
     pub fn is_comment(&self) -> bool {
         matches!(self, Self::Comment(_))
     }
+
+    /// How an instruction token is written in source
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        use Token::*;
+        Some(match self {
+            Nop => "nop",
+            DB => "db",
+            DW => "dw",
+
+            Mov => "mov",
+            Push => "push",
+            Pop => "pop",
+
+            Add => "add",
+            Sub => "sub",
+            Not => "not",
+            And => "and",
+            Or => "or",
+            Shl => "shl",
+            Shr => "shr",
+            Shre => "shre",
+            Cmp => "cmp",
+            Mul => "mul",
+            Div => "div",
+            Mod => "mod",
+
+            AJmp => "ajmp",
+            Jmp => "jmp",
+            Jeq => "jeq",
+            Jneq => "jneq",
+            Jlt => "jlt",
+            Jgt => "jgt",
+            Jleq => "jleq",
+            Jgeq => "jgeq",
+            Jo => "jo",
+            Jno => "jno",
+            Call => "call",
+            Ret => "ret",
+
+            Int => "int",
+            Sti => "sti",
+            Cli => "cli",
+
+            _ => return None,
+        })
+    }
 }
 
 pub type Tokens = Scanner<Token>;
@@ -85,6 +134,9 @@ fn convert_tokens(toks : Vec<PToken>) -> Result<Vec<Token>> {
             "shr" => ScannerAction::Return(Token::Shr),
             "shre" => ScannerAction::Return(Token::Shre),
             "cmp" => ScannerAction::Return(Token::Cmp),
+            "mul" => ScannerAction::Return(Token::Mul),
+            "div" => ScannerAction::Return(Token::Div),
+            "mod" => ScannerAction::Return(Token::Mod),
 
             "ajmp" => ScannerAction::Return(Token::AJmp),
             "jmp" => ScannerAction::Return(Token::Jmp),
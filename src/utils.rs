@@ -14,6 +14,18 @@ pub enum Error {
     #[error("identifier {0} not defined")]
     NoSuchIdentifier(String),
 
+    #[error("register {0} has no name")]
+    NoSuchRegister(String),
+
+    #[error("malformed directive {0}")]
+    Directive(String),
+
+    #[error("cannot include {0}")]
+    Include(String),
+
+    #[error("fault at {0:#06X}: {1}")]
+    Fault(u16, String),
+
     #[error("{0}")]
     CoreCommon(smpl_core_common::utils::Error),
 
@@ -27,3 +39,57 @@ impl From<smpl_core_common::utils::Error> for Error {
 				Self::CoreCommon(value)
 		}
 }
+
+/// Every register `tokenize` recognises, together with its name
+pub fn registers() -> &'static [(String, smpl_core_common::Register)] {
+    use std::{str::FromStr, sync::OnceLock};
+    use smpl_core_common::Register;
+
+    static REGISTERS : OnceLock<Vec<(String, Register)>> = OnceLock::new();
+    REGISTERS.get_or_init(|| ["r", "rb"].into_iter()
+        .flat_map(|prefix| (0..16).map(move |i| format!("{prefix}{i}")))
+        .filter_map(|name| Register::from_str(&name).ok().map(|reg| (name, reg)))
+        .collect())
+}
+
+/// Name of a register, as written in source
+pub fn register_name(reg : smpl_core_common::Register) -> &'static str {
+    registers().iter()
+        .find(|(_, r)| *r == reg)
+        .map(|(name, _)| name.as_str())
+        .unwrap_or("?")
+}
+
+/// Index of a register, and whether it's the byte half (`rbN`) of the word register `rN`
+pub fn register_index(reg : smpl_core_common::Register) -> Result<(usize, bool)> {
+    let name = register_name(reg);
+    let (idx, byte) = match name.strip_prefix("rb") {
+        Some(idx) => (idx, true),
+        None => (&name[1..], false),
+    };
+    idx.parse().map(|idx| (idx, byte)).map_err(|_| Error::NoSuchRegister(format!("{reg:?}")))
+}
+
+/// The word register `rN`
+pub fn word_register(idx : usize) -> Result<smpl_core_common::Register> {
+    registers().iter()
+        .find(|(name, _)| *name == format!("r{idx}"))
+        .map(|(_, reg)| *reg)
+        .ok_or(Error::External(format!("no register r{idx}")))
+}
+
+/// The byte register `rbN`
+pub fn byte_register(idx : usize) -> Result<smpl_core_common::Register> {
+    registers().iter()
+        .find(|(name, _)| *name == format!("rb{idx}"))
+        .map(|(_, reg)| *reg)
+        .ok_or(Error::External(format!("no register rb{idx}")))
+}
+
+/// Errors unless `bytes` fit in memory from `base`
+pub(crate) fn check_fits(bytes : &[u8], base : u16) -> Result<()> {
+    if base as usize + bytes.len() > 0x10000 {
+        return Err(Error::External(format!("{} bytes at {base:#06X} go past the end of memory", bytes.len())))
+    }
+    Ok(())
+}
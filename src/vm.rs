@@ -0,0 +1,542 @@
+//! An emulator for SmplCore, running programs straight out of memory.
+//!
+//! Besides the registers, the machine has 64 KiB of memory, the flags set by `cmp`, `add` and `sub`
+//! and a stack pointer growing down from [`STACK_TOP`]. Words are stored little endian.
+//! Devices can be mapped over ranges of memory, see [`Device`].
+//!
+//! `sti reg` enables interrupts, with the vector table of handler addresses at `reg`, and `cli`
+//! disables them. `int reg` raises interrupt `reg` whether they're enabled or not. Taking an
+//! interrupt pushes the return address and disables interrupts, and the handler's `ret` brings
+//! back the flags and interrupt state from before.
+//!
+//! The machine can keep a [`History`] of what its last instructions changed so they can be undone.
+//! [`Observer`]s can watch it run, to record its execution to be replayed, see [`record`], or to
+//! trace it, see [`trace`].
+
+use std::{collections::{HashMap, VecDeque}, fmt::Write};
+
+use smpl_core_common::Register;
+use crate::{Decoded, Program, Token, decode, utils::{self, Error, Result}};
+
+pub mod device;
+pub mod history;
+pub mod record;
+pub mod trace;
+pub use device::Device;
+pub use history::{Change, History};
+
+/// Registers in [`Vm::register_file`]
+pub const REGISTER_FILE : usize = 19;
+
+/// Name of register `i` of [`Vm::register_file`]
+pub fn register_name(i : usize) -> String {
+    match i {
+        0..=15 => format!("r{i}"),
+        16 => "sp".to_string(),
+        17 => "pc".to_string(),
+        _ => "flags".to_string(),
+    }
+}
+
+/// Something watching the machine run
+pub trait Observer {
+    /// Called after every instruction, with what it was decoded as before it ran. The change it
+    /// made is the last one in the machine's history.
+    fn observe(&mut self, vm : &Vm, decoded : &Decoded) -> Result<()>;
+
+    /// Called once the run's over
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The top of the stack, which `push` goes below, clear of the devices in [`device::DEFAULT_MAP`]
+pub const STACK_TOP : u16 = 0xFF00;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// The destination of the last `cmp` was equal to its source
+    pub zero : bool,
+    /// It was below the source, unsigned
+    pub less : bool,
+    /// It was above the source, unsigned
+    pub greater : bool,
+    /// The last `add` carried or `sub` borrowed
+    pub overflow : bool,
+}
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// A jump to itself, which would loop forever
+    Halted,
+    /// A `ret` with nothing on the stack
+    Returned,
+    CycleLimit,
+    /// A device asked to stop, with an exit code
+    Exit(u8),
+}
+
+#[derive(Debug)]
+pub struct Vm {
+    pub memory : Vec<u8>,
+    pub registers : [u16; 16],
+    pub pc : u16,
+    pub sp : u16,
+    pub flags : Flags,
+    /// Instructions executed so far
+    pub cycles : u64,
+    /// Whether interrupts are enabled
+    pub interrupts : bool,
+    /// Address of the interrupt vector table, once `sti` has set it
+    pub vectors : Option<u16>,
+    /// Interrupts raised while they were disabled, in the order they were first raised. Raising one
+    /// that's already pending does nothing, so there's at most one of each.
+    pub pending : VecDeque<u8>,
+    /// The last instructions run, kept to undo them when its capacity isn't 0
+    pub history : History,
+    decoded : HashMap<u16, (Vec<u8>, Decoded)>,
+    devices : Vec<(u16, Box<dyn Device>)>,
+    /// The stack pointer, flags and interrupt state to restore when each running handler returns
+    handlers : Vec<(u16, Flags, bool)>,
+    /// What the running instruction has done so far, when it's being kept track of
+    change : Option<Change>,
+    /// The recording being played back in place of the devices' input
+    replay : Option<record::Replay>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn width(reg : Register) -> Result<u16> {
+    Ok(if utils::register_index(reg)?.1 { 1 } else { 2 })
+}
+
+/// The device mapped over `address`, and how far into it the address is
+fn device(devices : &mut [(u16, Box<dyn Device>)], address : u16) -> Option<(u16, &mut dyn Device)> {
+    devices.iter_mut()
+        .find(|(base, device)| *base <= address && (address as usize) < *base as usize + device.len() as usize)
+        .map(|(base, device)| (address - *base, device.as_mut() as &mut dyn Device))
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; 0x10000],
+            registers: [0; 16],
+            pc: 0,
+            sp: STACK_TOP,
+            flags: Flags::default(),
+            cycles: 0,
+            interrupts: false,
+            vectors: None,
+            pending: VecDeque::new(),
+            history: History::default(),
+            decoded: HashMap::new(),
+            devices: Vec::new(),
+            handlers: Vec::new(),
+            change: None,
+            replay: None,
+        }
+    }
+
+    /// A machine with `program` loaded, about to run its first instruction
+    pub fn from_program(program : &Program) -> Self {
+        let mut vm = Self::new();
+        vm.load(&program.bytes(), program.base);
+        vm.pc = program.base;
+        vm
+    }
+
+    /// Copies `bytes` into memory at `base`
+    pub fn load(&mut self, bytes : &[u8], base : u16) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory[base.wrapping_add(i as u16) as usize] = *byte;
+        }
+    }
+
+    /// Maps `device` over memory from `base`
+    pub fn attach(&mut self, base : u16, device : Box<dyn Device>) -> Result<()> {
+        let end = base as usize + device.len() as usize;
+        let overlaps = self.devices.iter()
+            .any(|(start, other)| (base as usize) < *start as usize + other.len() as usize && (*start as usize) < end);
+        if overlaps || end > 0x10000 {
+            return Err(Error::External(format!("device at {base:#06X} overlaps another device or the end of memory")))
+        }
+        self.devices.push((base, device));
+        Ok(())
+    }
+
+
+    pub fn reg(&self, reg : Register) -> Result<u16> {
+        Ok(match utils::register_index(reg)? {
+            (idx, true) => self.registers[idx] & 0xFF,
+            (idx, false) => self.registers[idx],
+        })
+    }
+
+    /// Sets a register, `rbN` only changing the low byte of `rN`
+    pub fn set_reg(&mut self, reg : Register, value : u16) -> Result<()> {
+        match utils::register_index(reg)? {
+            (idx, true) => self.registers[idx] = self.registers[idx] & 0xFF00 | value & 0xFF,
+            (idx, false) => self.registers[idx] = value,
+        }
+        Ok(())
+    }
+
+    /// Reads memory, or the device mapped over it
+    pub fn read_byte(&mut self, address : u16) -> u8 {
+        let Some((offset, device)) = device(&mut self.devices, address) else { return self.memory[address as usize] };
+        let value = match &mut self.replay {
+            Some(replay) => replay.input(address),
+            None => device.read(offset),
+        };
+        if let Some(change) = &mut self.change {
+            change.inputs.push((address, value));
+        }
+        value
+    }
+
+    pub fn write_byte(&mut self, address : u16, value : u8) {
+        match device(&mut self.devices, address) {
+            Some((offset, device)) => device.write(offset, value),
+            None => {
+                let old = std::mem::replace(&mut self.memory[address as usize], value);
+                if let Some(change) = &mut self.change {
+                    change.writes.push((address, old, value));
+                }
+            },
+        }
+    }
+
+    pub fn read_word(&mut self, address : u16) -> u16 {
+        u16::from_le_bytes([self.read_byte(address), self.read_byte(address.wrapping_add(1))])
+    }
+
+    pub fn write_word(&mut self, address : u16, value : u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write_byte(address, lo);
+        self.write_byte(address.wrapping_add(1), hi);
+    }
+
+    /// Reads `width` bytes, 1 or 2
+    fn read(&mut self, address : u16, width : u16) -> u16 {
+        if width == 1 { self.read_byte(address) as u16 } else { self.read_word(address) }
+    }
+
+    fn write(&mut self, address : u16, width : u16, value : u16) {
+        if width == 1 { self.write_byte(address, value as u8) } else { self.write_word(address, value) }
+    }
+
+    pub fn push(&mut self, width : u16, value : u16) {
+        self.sp = self.sp.wrapping_sub(width);
+        self.write(self.sp, width, value);
+    }
+
+    pub fn pop(&mut self, width : u16) -> u16 {
+        let value = self.read(self.sp, width);
+        self.sp = self.sp.wrapping_add(width);
+        value
+    }
+
+    /// Decodes the instruction at `address`, straight from memory
+    pub fn fetch(&mut self, address : u16) -> Result<Decoded> {
+        let bytes = |memory : &[u8], len : usize| -> Vec<u8> {
+            (0..len).map(|i| memory[address.wrapping_add(i as u16) as usize]).collect()
+        };
+
+        // Memory may have been written since, so cached instructions are checked against it
+        if let Some((cached, decoded)) = self.decoded.get(&address) {
+            if *cached == bytes(&self.memory, cached.len()) {
+                return Ok(decoded.clone())
+            }
+        }
+
+        let window = bytes(&self.memory, 8);
+        match decode(&window) {
+            Some(decoded) if decoded.op != Token::DB => {
+                self.decoded.insert(address, (window[..decoded.len() as usize].to_vec(), decoded.clone()));
+                Ok(decoded)
+            },
+            _ => Err(Error::Fault(address, format!("invalid instruction {:#04X}", window[0]))),
+        }
+    }
+
+    fn value(&self, operand : &Token) -> Result<u16> {
+        match operand {
+            Token::Number(value) => Ok(*value as u16),
+            Token::Register(reg) => self.reg(*reg),
+            _ => Err(Error::Fault(self.pc, format!("unexpected operand {operand:?}"))),
+        }
+    }
+
+    /// Raises interrupt `vector`, taken once interrupts are enabled
+    pub fn raise(&mut self, vector : u8) {
+        if !self.pending.contains(&vector) {
+            self.pending.push_back(vector);
+        }
+    }
+
+    /// Calls the handler of interrupt `vector`
+    fn enter(&mut self, vector : u8) -> Result<()> {
+        let Some(vectors) = self.vectors else {
+            return Err(Error::Fault(self.pc, format!("interrupt {vector} with no vector table")))
+        };
+        let handler = self.read_word(vectors.wrapping_add(vector as u16 * 2));
+        self.handlers.push((self.sp, self.flags, self.interrupts));
+        self.push(2, self.pc);
+        self.interrupts = false;
+        self.pc = handler;
+        Ok(())
+    }
+
+    /// Runs one instruction, returning why the machine stopped if it did
+    pub fn step(&mut self) -> Result<Option<Stop>> {
+        if self.history.capacity == 0 && self.replay.is_none() {
+            return self.execute()
+        }
+
+        if let Some(replay) = &mut self.replay {
+            replay.begin(self.cycles)?;
+        }
+        self.change = Some(self.save());
+        let res = self.execute();
+        let change = self.change.take().unwrap();
+        let registers = self.register_file();
+        let checked = match &mut self.replay {
+            Some(replay) => replay.check(&change, &registers),
+            None => Ok(()),
+        };
+        self.history.push(change);
+        res.and_then(|stop| checked.map(|_| stop))
+    }
+
+    /// Plays `recording` back from here on, in place of the devices' input
+    pub fn replay(&mut self, recording : Vec<record::Entry>) {
+        self.replay = Some(record::Replay::new(recording, self.register_file()));
+    }
+
+    fn execute(&mut self) -> Result<Option<Stop>> {
+        use Token::*;
+        let decoded = self.fetch(self.pc)?;
+        let pc = self.pc;
+        let next = pc.wrapping_add(decoded.len());
+        let fault = |what : &str| Err(Error::Fault(pc, format!("{what} {decoded}")));
+        self.pc = next;
+        self.cycles += 1;
+
+        let mut jump = None;
+        match (&decoded.op, &decoded.operands[..]) {
+            (Nop, []) => (),
+
+            (Mov, [Pointer(src), Register(dest)]) => {
+                let value = self.read(self.reg(*src)?, width(*dest)?);
+                self.set_reg(*dest, value)?;
+            },
+            (Mov, [Register(src), Pointer(dest)]) => self.write(self.reg(*dest)?, width(*src)?, self.reg(*src)?),
+            (Mov, [src, Register(dest)]) => self.set_reg(*dest, self.value(src)?)?,
+            (Push, [Register(reg)]) => self.push(width(*reg)?, self.reg(*reg)?),
+            (Pop, [Register(reg)]) => {
+                let value = self.pop(width(*reg)?);
+                self.set_reg(*reg, value)?;
+            },
+
+            (Cmp, [src, Register(dest)]) => {
+                let (src, value) = (self.value(src)?, self.reg(*dest)?);
+                self.flags.zero = value == src;
+                self.flags.less = value < src;
+                self.flags.greater = value > src;
+            },
+            (op @ (Add | Sub | And | Or | Shl | Shr | Shre), [src, Register(dest)]) => {
+                let (src, value, width) = (self.value(src)?, self.reg(*dest)?, width(*dest)?);
+                let mask = if width == 1 { 0xFF } else { 0xFFFF };
+                let sign = if width == 1 { 0x80 } else { 0x8000 };
+                let res = match op {
+                    Add => {
+                        self.flags.overflow = value as u32 + src as u32 > mask as u32;
+                        value.wrapping_add(src)
+                    },
+                    Sub => {
+                        self.flags.overflow = src > value;
+                        value.wrapping_sub(src)
+                    },
+                    And => value & src,
+                    Or => value | src,
+                    Shl => value.checked_shl(src as u32).unwrap_or(0),
+                    Shr => value.checked_shr(src as u32).unwrap_or(0),
+                    // Shifts in copies of the sign bit
+                    _ => (0..src.min(16)).fold(value, |value, _| value >> 1 | value & sign),
+                };
+                self.set_reg(*dest, res & mask)?;
+            },
+            (Not, [Register(reg)]) => self.set_reg(*reg, !self.reg(*reg)?)?,
+
+            (AJmp, [Register(reg)]) => jump = Some(self.reg(*reg)?),
+            (op @ (Jmp | Jeq | Jneq | Jlt | Jgt | Jleq | Jgeq | Jo | Jno), [Register(reg)]) => {
+                let Flags { zero, less, greater, overflow } = self.flags;
+                let taken = match op {
+                    Jeq => zero,
+                    Jneq => !zero,
+                    Jlt => less,
+                    Jgt => greater,
+                    Jleq => less || zero,
+                    Jgeq => greater || zero,
+                    Jo => overflow,
+                    Jno => !overflow,
+                    _ => true,
+                };
+                if taken {
+                    jump = Some(next.wrapping_add(self.reg(*reg)?));
+                }
+            },
+            (Call, [target]) => {
+                let target = self.value(target)?;
+                self.push(2, next);
+                self.pc = target;
+            },
+            (Ret, []) if self.sp == STACK_TOP => return Ok(Some(Stop::Returned)),
+            (Ret, []) => {
+                self.pc = self.pop(2);
+                // Handlers whose frames have been popped some other way are dropped along the way
+                let depth = |sp : u16| STACK_TOP.wrapping_sub(sp);
+                while let Some((sp, flags, interrupts)) = self.handlers.last().copied() {
+                    if depth(sp) > depth(self.sp) {
+                        self.handlers.pop();
+                        continue
+                    }
+                    if sp == self.sp {
+                        self.handlers.pop();
+                        self.flags = flags;
+                        self.interrupts = interrupts;
+                    }
+                    break
+                }
+            },
+
+            (Sti, [Register(reg)]) => {
+                self.vectors = Some(self.reg(*reg)?);
+                self.interrupts = true;
+            },
+            (Cli, []) => self.interrupts = false,
+            (Int, [Register(reg)]) => self.enter(self.reg(*reg)? as u8)?,
+            _ => return fault("can't execute"),
+        }
+
+        match jump {
+            Some(target) if target == pc => return Ok(Some(Stop::Halted)),
+            Some(target) => self.pc = target,
+            None => (),
+        }
+
+        let mut stop = None;
+        let mut raised = Vec::new();
+        for (_, device) in self.devices.iter_mut() {
+            stop = stop.or(device.tick());
+            raised.extend(device.interrupt());
+        }
+        if let Some(replay) = &self.replay {
+            raised = replay.raised();
+        }
+        if let Some(change) = &mut self.change {
+            change.raised.extend(&raised);
+        }
+        for vector in raised.into_iter() {
+            self.raise(vector);
+        }
+        if stop.is_none() && self.interrupts {
+            if let Some(vector) = self.pending.pop_front() {
+                self.enter(vector)?;
+            }
+        }
+        Ok(stop)
+    }
+
+    /// Runs until the machine stops, or `max_cycles` more instructions have run
+    pub fn run(&mut self, max_cycles : Option<u64>) -> Result<Stop> {
+        let limit = max_cycles.map(|max| self.cycles + max);
+        loop {
+            if limit.is_some_and(|limit| self.cycles >= limit) {
+                return Ok(Stop::CycleLimit)
+            }
+            if let Some(stop) = self.step()? {
+                return Ok(stop)
+            }
+        }
+    }
+
+    /// Runs like [`Vm::run`], telling `observers` about every instruction. The history keeps at
+    /// least the last one for them.
+    pub fn run_observed(&mut self, max_cycles : Option<u64>, observers : &mut [&mut dyn Observer]) -> Result<Stop> {
+        self.history.capacity = self.history.capacity.max(1);
+        let limit = max_cycles.map(|max| self.cycles + max);
+        let res = loop {
+            if limit.is_some_and(|limit| self.cycles >= limit) {
+                break Ok(Stop::CycleLimit)
+            }
+            let decoded = match self.fetch(self.pc) {
+                Ok(decoded) => decoded,
+                Err(err) => break Err(err),
+            };
+            let stepped = self.step();
+            if let Err(err) = observers.iter_mut().try_for_each(|observer| observer.observe(self, &decoded)) {
+                break Err(err)
+            }
+            match stepped {
+                Ok(Some(stop)) => break Ok(stop),
+                Ok(None) => (),
+                Err(err) => break Err(err),
+            }
+        };
+        for observer in observers.iter_mut() {
+            observer.finish()?;
+        }
+        res
+    }
+
+    /// Every register, the flags and the cycle count
+    pub fn dump(&self) -> String {
+        let mut res = String::new();
+        for (idx, value) in self.registers.iter().enumerate() {
+            let sep = if idx % 4 == 3 { "\n" } else { "  " };
+            write!(res, "r{idx:<2} = {value:#06X}{sep}").unwrap();
+        }
+        writeln!(res, "pc  = {:#06X}  sp  = {:#06X}  flags = {}  cycles = {}", self.pc, self.sp, self.flag_names(), self.cycles).unwrap();
+        res
+    }
+
+    /// The registers as gdb and recordings see them: `r0` to `r15`, `sp`, `pc`, then the flags and
+    /// interrupt state as bits, zero first
+    pub fn register_file(&self) -> [u16; REGISTER_FILE] {
+        let Flags { zero, less, greater, overflow } = self.flags;
+        let flags = [zero, less, greater, overflow, self.interrupts].iter().rev().fold(0, |flags, set| flags << 1 | *set as u16);
+        let mut res = [0; REGISTER_FILE];
+        res[..16].copy_from_slice(&self.registers);
+        res[16..].copy_from_slice(&[self.sp, self.pc, flags]);
+        res
+    }
+
+    /// Sets register `i` of [`Vm::register_file`]
+    pub fn set_register(&mut self, i : usize, value : u16) {
+        match i {
+            0..=15 => self.registers[i] = value,
+            16 => self.sp = value,
+            17 => self.pc = value,
+            _ => {
+                let bit = |i : u16| value & 1 << i != 0;
+                self.flags = Flags { zero: bit(0), less: bit(1), greater: bit(2), overflow: bit(3) };
+                self.interrupts = bit(4);
+            },
+        }
+    }
+
+    /// The flags and interrupt state as `ZLGOI`, with a `-` for each one that's clear
+    pub fn flag_names(&self) -> String {
+        let Flags { zero, less, greater, overflow } = self.flags;
+        [(zero, 'Z'), (less, 'L'), (greater, 'G'), (overflow, 'O'), (self.interrupts, 'I')].iter()
+            .map(|(set, name)| if *set { *name } else { '-' })
+            .collect()
+    }
+}
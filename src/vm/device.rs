@@ -0,0 +1,236 @@
+use std::{fmt::Debug, io::{Read, Write}, sync::{Arc, Mutex, mpsc::{self, Receiver, TryRecvError}}};
+
+use crate::utils::{Error, Result};
+use super::{Stop, Vm};
+
+/// Something mapped into a range of memory
+pub trait Device : Debug {
+    /// Bytes of memory the device takes up
+    #[allow(clippy::len_without_is_empty)]
+    fn len(&self) -> u16;
+
+    /// Reads the byte `offset` into the device's range
+    fn read(&mut self, offset : u16) -> u8;
+
+    fn write(&mut self, offset : u16, value : u8);
+
+    /// Runs after every instruction, and can stop the machine
+    fn tick(&mut self) -> Option<Stop> {
+        None
+    }
+
+    /// An interrupt the device is raising, checked after every tick
+    fn interrupt(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// Writes every byte stored to it to stdout, or to a buffer
+#[derive(Debug, Default)]
+pub struct Console {
+    pub buffer : Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl Console {
+    /// A console collecting its output in `buffer` instead of printing it
+    pub fn buffered(buffer : Arc<Mutex<Vec<u8>>>) -> Self {
+        Self { buffer: Some(buffer) }
+    }
+}
+
+impl Device for Console {
+    fn len(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _ : u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _ : u16, value : u8) {
+        match &self.buffer {
+            Some(buffer) => buffer.lock().unwrap().push(value),
+            None => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+            },
+        }
+    }
+}
+
+/// Reads stdin without blocking. The first byte is a status, bit 0 being set when there's a byte
+/// waiting and bit 1 once the input has ended, and reading the second takes the waiting byte.
+#[derive(Debug)]
+pub struct Input {
+    rx : Receiver<u8>,
+    waiting : Option<u8>,
+    ended : bool,
+}
+
+impl Input {
+    pub const READY : u8 = 0x01;
+    pub const ENDED : u8 = 0x02;
+
+    /// Reads stdin from a thread of its own
+    pub fn stdin() -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if tx.send(byte).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+        Self { rx, waiting: None, ended: false }
+    }
+
+    /// Input that's known ahead of time
+    pub fn from_bytes(bytes : &[u8]) -> Self {
+        let (tx, rx) = mpsc::channel();
+        for byte in bytes.iter() {
+            tx.send(*byte).unwrap();
+        }
+        Self { rx, waiting: None, ended: false }
+    }
+
+    fn poll(&mut self) {
+        if self.waiting.is_none() && !self.ended {
+            match self.rx.try_recv() {
+                Ok(byte) => self.waiting = Some(byte),
+                Err(TryRecvError::Disconnected) => self.ended = true,
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+    }
+}
+
+impl Device for Input {
+    fn len(&self) -> u16 {
+        2
+    }
+
+    fn read(&mut self, offset : u16) -> u8 {
+        self.poll();
+        match offset {
+            0 => if self.waiting.is_some() { Self::READY } else if self.ended { Self::ENDED } else { 0 },
+            _ => self.waiting.take().unwrap_or(0),
+        }
+    }
+
+    fn write(&mut self, _ : u16, _ : u8) {}
+}
+
+/// Stops the machine when written to, the byte written being the exit code
+#[derive(Debug, Default)]
+pub struct Exit {
+    code : Option<u8>,
+}
+
+impl Device for Exit {
+    fn len(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _ : u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _ : u16, value : u8) {
+        self.code = Some(value);
+    }
+
+    fn tick(&mut self) -> Option<Stop> {
+        self.code.take().map(Stop::Exit)
+    }
+}
+
+/// Raises an interrupt every so many cycles. The first two bytes are the period, a little endian
+/// word with 0 stopping the timer, and the third is the interrupt raised.
+#[derive(Debug, Default)]
+pub struct Timer {
+    period : u16,
+    vector : u8,
+    count : u16,
+    fired : bool,
+}
+
+impl Device for Timer {
+    fn len(&self) -> u16 {
+        3
+    }
+
+    fn read(&mut self, offset : u16) -> u8 {
+        match offset {
+            0 => self.period as u8,
+            1 => (self.period >> 8) as u8,
+            _ => self.vector,
+        }
+    }
+
+    fn write(&mut self, offset : u16, value : u8) {
+        match offset {
+            0 => self.period = self.period & 0xFF00 | value as u16,
+            1 => self.period = self.period & 0x00FF | (value as u16) << 8,
+            _ => {
+                self.vector = value;
+                return
+            },
+        }
+        self.count = 0;
+    }
+
+    fn tick(&mut self) -> Option<Stop> {
+        if self.period != 0 {
+            self.count += 1;
+            if self.count >= self.period {
+                self.count = 0;
+                self.fired = true;
+            }
+        }
+        None
+    }
+
+    fn interrupt(&mut self) -> Option<u8> {
+        std::mem::take(&mut self.fired).then_some(self.vector)
+    }
+}
+
+/// Where the built in devices go unless told otherwise
+pub const DEFAULT_MAP : [(&str, u16); 4] = [("console", 0xFF00), ("input", 0xFF01), ("exit", 0xFF03), ("timer", 0xFF04)];
+
+/// A built in device by name, talking to stdin and stdout
+pub fn create(name : &str) -> Result<Box<dyn Device>> {
+    match name {
+        "console" => Ok(Box::new(Console::default())),
+        "input" => Ok(Box::new(Input::stdin())),
+        "exit" => Ok(Box::new(Exit::default())),
+        "timer" => Ok(Box::new(Timer::default())),
+        _ => Err(Error::External(format!("no device called {name}"))),
+    }
+}
+
+/// Attaches the built in devices at their usual addresses, with the console writing to `console`
+/// and nothing to input
+pub fn attach_default(vm : &mut Vm, console : Arc<Mutex<Vec<u8>>>) -> Result<()> {
+    for (name, address) in DEFAULT_MAP {
+        let device : Box<dyn Device> = match name {
+            "console" => Box::new(Console::buffered(console.clone())),
+            "input" => Box::new(Input::from_bytes(&[])),
+            name => create(name)?,
+        };
+        vm.attach(address, device)?;
+    }
+    Ok(())
+}
+
+/// Reads a `name@address` mapping
+pub fn parse_mapping(mapping : &str) -> Result<(String, u16)> {
+    let malformed = || Error::External(format!("malformed device mapping {mapping}, expected name@address"));
+    let (name, address) = mapping.split_once('@').ok_or_else(malformed)?;
+    let address = match address.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => address.parse(),
+    }.map_err(|_| malformed())?;
+    Ok((name.to_string(), address))
+}
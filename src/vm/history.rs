@@ -0,0 +1,121 @@
+//! Undoing instructions, for running the machine backwards.
+//!
+//! Only the machine itself is put back: devices keep whatever state they're in, and anything
+//! they've printed stays printed.
+
+use std::collections::VecDeque;
+
+use super::{Flags, Vm};
+
+/// Everything outside memory an instruction can change
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Saved {
+    registers : [u16; 16],
+    pc : u16,
+    sp : u16,
+    flags : Flags,
+    cycles : u64,
+    interrupts : bool,
+    vectors : Option<u16>,
+    pending : VecDeque<u8>,
+    handlers : Vec<(u16, Flags, bool)>,
+}
+
+/// What an instruction changed, enough to undo it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    saved : Saved,
+    /// Bytes of memory written, with the value before and after
+    pub writes : Vec<(u16, u8, u8)>,
+    /// Bytes read from devices
+    pub inputs : Vec<(u16, u8)>,
+    /// Interrupts devices raised
+    pub raised : Vec<u8>,
+}
+
+impl Change {
+    /// Address of the instruction
+    pub fn at(&self) -> u16 {
+        self.saved.pc
+    }
+
+    /// Instructions run before it
+    pub fn cycle(&self) -> u64 {
+        self.saved.cycles
+    }
+}
+
+/// The changes made by the last `capacity` instructions, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    changes : VecDeque<Change>,
+    pub capacity : usize,
+}
+
+impl History {
+    pub fn new(capacity : usize) -> Self {
+        Self { changes: VecDeque::new(), capacity }
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The change made by the last instruction
+    pub fn last(&self) -> Option<&Change> {
+        self.changes.back()
+    }
+
+    pub(super) fn push(&mut self, change : Change) {
+        self.changes.push_back(change);
+        while self.changes.len() > self.capacity {
+            self.changes.pop_front();
+        }
+    }
+}
+
+impl Vm {
+    /// A change with nothing in it yet, for the instruction about to run
+    pub(super) fn save(&self) -> Change {
+        let saved = Saved {
+            registers: self.registers,
+            pc: self.pc,
+            sp: self.sp,
+            flags: self.flags,
+            cycles: self.cycles,
+            interrupts: self.interrupts,
+            vectors: self.vectors,
+            pending: self.pending.clone(),
+            handlers: self.handlers.clone(),
+        };
+        Change { saved, writes: Vec::new(), inputs: Vec::new(), raised: Vec::new() }
+    }
+
+    /// Undoes the last instruction in the history, returning its change, or `None` if the history's empty
+    pub fn undo(&mut self) -> Option<Change> {
+        let change = self.history.changes.pop_back()?;
+        for (address, old, _) in change.writes.iter().rev() {
+            self.memory[*address as usize] = *old;
+        }
+        let saved = change.saved.clone();
+        self.registers = saved.registers;
+        self.pc = saved.pc;
+        self.sp = saved.sp;
+        self.flags = saved.flags;
+        self.cycles = saved.cycles;
+        self.interrupts = saved.interrupts;
+        self.vectors = saved.vectors;
+        self.pending = saved.pending;
+        self.handlers = saved.handlers;
+
+        let registers = self.register_file();
+        if let Some(replay) = &mut self.replay {
+            replay.rewind(registers);
+        }
+        Some(change)
+    }
+}
@@ -0,0 +1,196 @@
+//! Recording execution to replay it later.
+//!
+//! A recording is text, a `sasm recording` line followed by a line per instruction run:
+//!
+//! ```text
+//! @0004 r0=0068 pc=0006 iFF10=68
+//! @0006 pc=0008 m8000=68 irq=01
+//! ```
+//!
+//! Each line starts with the address of the instruction, then has the registers of
+//! [`Vm::register_file`] that changed, the bytes of memory written (`m`), the bytes read from
+//! devices (`i`) and the interrupts devices raised (`irq`), all in hex. Replaying feeds the device
+//! reads and interrupts back in place of the devices', and checks the machine does the same again.
+
+use std::{fmt::{self, Display}, io::Write};
+
+use crate::{Decoded, utils::{Error, Result}};
+use super::{Change, Observer, REGISTER_FILE, Stop, Vm, register_name};
+
+const HEADER : &str = "sasm recording";
+
+/// What one instruction did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// Address of the instruction
+    pub at : u16,
+    /// Registers that changed, by their index in [`Vm::register_file`], with their new values
+    pub registers : Vec<(usize, u16)>,
+    /// Bytes of memory written, with their new values
+    pub writes : Vec<(u16, u8)>,
+    /// Bytes read from devices
+    pub inputs : Vec<(u16, u8)>,
+    /// Interrupts devices raised
+    pub raised : Vec<u8>,
+}
+
+impl Entry {
+    /// The entry for `change`, with the registers from before and after it
+    pub fn new(change : &Change, before : &[u16; REGISTER_FILE], after : &[u16; REGISTER_FILE]) -> Self {
+        Self {
+            at: change.at(),
+            registers: (0..REGISTER_FILE).filter(|i| before[*i] != after[*i]).map(|i| (i, after[i])).collect(),
+            writes: change.writes.iter().map(|(address, _, new)| (*address, *new)).collect(),
+            inputs: change.inputs.clone(),
+            raised: change.raised.clone(),
+        }
+    }
+
+    pub fn parse(line : &str) -> Result<Self> {
+        let malformed = || Error::External(format!("malformed recording line {line}"));
+        let hex = |text : &str| u16::from_str_radix(text, 16).map_err(|_| malformed());
+        let byte = |text : &str| u8::from_str_radix(text, 16).map_err(|_| malformed());
+
+        let mut words = line.split_whitespace();
+        let at = words.next().and_then(|at| at.strip_prefix('@')).ok_or_else(malformed)?;
+        let mut res = Self { at: hex(at)?, ..Self::default() };
+        for word in words {
+            let (key, value) = word.split_once('=').ok_or_else(malformed)?;
+            if key == "irq" {
+                res.raised.push(byte(value)?);
+            } else if let Some(i) = (0..REGISTER_FILE).find(|i| register_name(*i) == key) {
+                res.registers.push((i, hex(value)?));
+            } else if let Some(address) = key.strip_prefix('m') {
+                res.writes.push((hex(address)?, byte(value)?));
+            } else if let Some(address) = key.strip_prefix('i') {
+                res.inputs.push((hex(address)?, byte(value)?));
+            } else {
+                return Err(malformed())
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{:04X}", self.at)?;
+        for (i, value) in self.registers.iter() {
+            write!(f, " {}={value:04X}", register_name(*i))?;
+        }
+        for (address, value) in self.writes.iter() {
+            write!(f, " m{address:04X}={value:02X}")?;
+        }
+        for (address, value) in self.inputs.iter() {
+            write!(f, " i{address:04X}={value:02X}")?;
+        }
+        for vector in self.raised.iter() {
+            write!(f, " irq={vector:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a recording back
+pub fn read(text : &str) -> Result<Vec<Entry>> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    if lines.next() != Some(HEADER) {
+        return Err(Error::External("not a sasm recording".to_string()))
+    }
+    lines.map(Entry::parse).collect()
+}
+
+/// Writes down the instructions a machine runs
+pub struct Recorder<W : Write> {
+    writer : W,
+    registers : [u16; REGISTER_FILE],
+}
+
+impl<W : Write> Recorder<W> {
+    /// Starts recording `vm` from where it is now
+    pub fn new(mut writer : W, vm : &Vm) -> Result<Self> {
+        writeln!(writer, "{HEADER}").map_err(|err| Error::External(err.to_string()))?;
+        Ok(Self { writer, registers: vm.register_file() })
+    }
+}
+
+impl<W : Write> Observer for Recorder<W> {
+    fn observe(&mut self, vm : &Vm, _ : &Decoded) -> Result<()> {
+        let Some(change) = vm.history.last() else { return Ok(()) };
+        let registers = vm.register_file();
+        writeln!(self.writer, "{}", Entry::new(change, &self.registers, &registers)).map_err(|err| Error::External(err.to_string()))?;
+        self.registers = registers;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush().map_err(|err| Error::External(err.to_string()))
+    }
+}
+
+/// Runs `vm` like [`Vm::run`], recording every instruction to `writer`
+pub fn record(vm : &mut Vm, max_cycles : Option<u64>, writer : impl Write) -> Result<Stop> {
+    let mut recorder = Recorder::new(writer, vm)?;
+    vm.run_observed(max_cycles, &mut [&mut recorder])
+}
+
+/// A recording being played back
+#[derive(Debug)]
+pub(super) struct Replay {
+    entries : Vec<Entry>,
+    /// The entry for the instruction running, or about to
+    next : usize,
+    /// Device reads the running instruction has made
+    read : usize,
+    /// The registers from before the running instruction
+    registers : [u16; REGISTER_FILE],
+}
+
+impl Replay {
+    pub(super) fn new(entries : Vec<Entry>, registers : [u16; REGISTER_FILE]) -> Self {
+        Self { entries, next: 0, read: 0, registers }
+    }
+
+    /// Gets ready for the next instruction
+    pub(super) fn begin(&mut self, cycles : u64) -> Result<()> {
+        if self.next >= self.entries.len() {
+            return Err(Error::External(format!("the recording ends at cycle {cycles}")))
+        }
+        self.read = 0;
+        Ok(())
+    }
+
+    /// The byte the recorded run read from `address`
+    pub(super) fn input(&mut self, address : u16) -> u8 {
+        let value = self.entries.get(self.next)
+            .and_then(|entry| entry.inputs.get(self.read))
+            .filter(|(recorded, _)| *recorded == address)
+            .map_or(0, |(_, value)| *value);
+        self.read += 1;
+        value
+    }
+
+    /// The interrupts devices raised in the recorded run
+    pub(super) fn raised(&self) -> Vec<u8> {
+        self.entries.get(self.next).map(|entry| entry.raised.clone()).unwrap_or_default()
+    }
+
+    /// Checks the instruction just run did what the recorded one did
+    pub(super) fn check(&mut self, change : &Change, registers : &[u16; REGISTER_FILE]) -> Result<()> {
+        let actual = Entry::new(change, &self.registers, registers);
+        let expected = &self.entries[self.next];
+        self.registers = *registers;
+        self.next += 1;
+        if actual == *expected {
+            Ok(())
+        } else {
+            Err(Error::External(format!("replay diverged at cycle {}: recorded `{expected}`, ran `{actual}`", change.cycle())))
+        }
+    }
+
+    /// Goes back an instruction, the registers being put back to `registers`
+    pub(super) fn rewind(&mut self, registers : [u16; REGISTER_FILE]) {
+        self.next = self.next.saturating_sub(1);
+        self.registers = registers;
+    }
+}
@@ -0,0 +1,147 @@
+//! Tracing execution, an instruction at a time or as waveforms.
+
+use std::io::Write;
+
+use crate::{Decoded, json::Json, utils::{Error, Result}};
+use super::{Observer, REGISTER_FILE, Vm, register_name};
+
+/// Index of `pc` in [`Vm::register_file`], left out of traces since every line has it anyway
+const PC : usize = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A line per instruction: the cycle, address, instruction, then what it changed
+    Text,
+    /// A JSON object per line, with `cycle`, `pc`, `instruction`, `registers` and `writes`
+    Json,
+}
+
+/// Writes a line for every instruction run
+pub struct Tracer<W : Write> {
+    writer : W,
+    format : Format,
+    registers : [u16; REGISTER_FILE],
+}
+
+impl<W : Write> Tracer<W> {
+    /// Starts tracing `vm` from where it is now
+    pub fn new(writer : W, format : Format, vm : &Vm) -> Self {
+        Self { writer, format, registers: vm.register_file() }
+    }
+}
+
+impl<W : Write> Observer for Tracer<W> {
+    fn observe(&mut self, vm : &Vm, decoded : &Decoded) -> Result<()> {
+        let Some(change) = vm.history.last() else { return Ok(()) };
+        let registers = vm.register_file();
+        let changed : Vec<usize> = (0..REGISTER_FILE).filter(|i| *i != PC && registers[*i] != self.registers[*i]).collect();
+        self.registers = registers;
+
+        let line = match self.format {
+            Format::Text => {
+                let mut line = format!("{:>8}  {:04X}  {:<24}", change.cycle(), change.at(), decoded.to_string());
+                for i in changed.iter() {
+                    if *i == REGISTER_FILE - 1 {
+                        line += &format!(" flags={}", vm.flag_names());
+                    } else {
+                        line += &format!(" {}={:#06X}", register_name(*i), registers[*i]);
+                    }
+                }
+                for (address, _, value) in change.writes.iter() {
+                    line += &format!(" [{address:#06X}]={value:#04X}");
+                }
+                line.trim_end().to_string()
+            },
+            Format::Json => Json::object([
+                ("cycle", Json::from(change.cycle())),
+                ("pc", Json::from(change.at())),
+                ("instruction", Json::from(decoded.to_string())),
+                ("registers", Json::Object(changed.iter().map(|i| (register_name(*i), Json::from(registers[*i]))).collect())),
+                ("writes", Json::from(change.writes.iter()
+                    .map(|(address, _, value)| Json::object([("address", Json::from(*address)), ("value", Json::from(*value))]))
+                    .collect::<Vec<_>>())),
+            ]).to_string(),
+        };
+        writeln!(self.writer, "{line}").map_err(|err| Error::External(err.to_string()))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush().map_err(|err| Error::External(err.to_string()))
+    }
+}
+
+/// Names of the flag bits at the end of [`Vm::register_file`], zero first
+const FLAGS : [&str; 5] = ["zero", "less", "greater", "overflow", "interrupts"];
+
+/// Writes a Value Change Dump of the registers, the flags as single bits, with a time step per
+/// instruction
+pub struct Vcd<W : Write> {
+    writer : W,
+    values : Vec<u16>,
+}
+
+/// The value of every signal: the registers but the flags, then a bit per flag
+fn signals(vm : &Vm) -> Vec<u16> {
+    let registers = vm.register_file();
+    let flags = registers[REGISTER_FILE - 1];
+    registers[..REGISTER_FILE - 1].iter().copied()
+        .chain((0..FLAGS.len()).map(|bit| flags >> bit & 1))
+        .collect()
+}
+
+/// The identifier of signal `i`, from `!` on
+fn code(i : usize) -> char {
+    (b'!' + i as u8) as char
+}
+
+impl<W : Write> Vcd<W> {
+    /// Writes the header and the state of `vm` now, at time 0
+    pub fn new(mut writer : W, vm : &Vm) -> Result<Self> {
+        let values = signals(vm);
+        let mut header = String::from("$version sasm $end\n$timescale 1ns $end\n$scope module smplcore $end\n");
+        for (i, name) in (0..REGISTER_FILE - 1).map(register_name).chain(FLAGS.iter().map(|flag| flag.to_string())).enumerate() {
+            let width = if i < REGISTER_FILE - 1 { 16 } else { 1 };
+            header += &format!("$var reg {width} {} {name} $end\n", code(i));
+        }
+        header += "$upscope $end\n$enddefinitions $end\n#0\n$dumpvars\n";
+
+        let mut vcd = Self { writer, values: Vec::new() };
+        header += &vcd.changes(&values);
+        header += "$end\n";
+        vcd.writer.write_all(header.as_bytes()).map_err(|err| Error::External(err.to_string()))?;
+        vcd.values = values;
+        Ok(vcd)
+    }
+
+    /// Lines for the signals that changed to `values`, every one if there were none before
+    fn changes(&self, values : &[u16]) -> String {
+        let mut res = String::new();
+        for (i, value) in values.iter().enumerate() {
+            if self.values.get(i) == Some(value) {
+                continue
+            }
+            if i < REGISTER_FILE - 1 {
+                res += &format!("b{value:b} {}\n", code(i));
+            } else {
+                res += &format!("{value}{}\n", code(i));
+            }
+        }
+        res
+    }
+}
+
+impl<W : Write> Observer for Vcd<W> {
+    fn observe(&mut self, vm : &Vm, _ : &Decoded) -> Result<()> {
+        let values = signals(vm);
+        let changes = self.changes(&values);
+        self.values = values;
+        if changes.is_empty() {
+            return Ok(())
+        }
+        write!(self.writer, "#{}\n{changes}", vm.cycles).map_err(|err| Error::External(err.to_string()))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush().map_err(|err| Error::External(err.to_string()))
+    }
+}
@@ -0,0 +1,34 @@
+//! Running the `sasm` binary the way it's used from a terminal
+
+use std::{io::Write, process::{Command, Stdio}, time::{Duration, Instant}};
+
+#[test]
+fn debug_reads_commands_from_stdin() {
+    let path = std::env::temp_dir().join(format!("sasm_cli_debug_{}.sasm", std::process::id()));
+    std::fs::write(&path, "start: mov 0x2A, r0\nret\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sasm"))
+        .arg("debug")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"step\nregs\nquit\n").unwrap();
+
+    // Commands lost to the program would leave it waiting for more
+    let start = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > Duration::from_secs(10) {
+            child.kill().unwrap();
+            panic!("sasm debug didn't quit");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(output.contains("0x002A"), "{output}");
+}